@@ -0,0 +1,21 @@
+use fib::{sync::mpsc::{self, RecvError}, task};
+
+// `main` keeps its return type, so `?` works inside the root fiber.
+#[fib::main(stack_size = 65536, max_tasks = 8)]
+fn main() -> Result<(), RecvError> {
+    let (tx, rx) = mpsc::channel();
+    for i in 0..4 {
+        let tx = tx.clone();
+        task::spawn(move || {
+            tx.send(i * i).unwrap();
+        });
+    }
+    drop(tx);
+
+    let mut sum = 0;
+    for _ in 0..4 {
+        sum += rx.recv()?;
+    }
+    println!("Sum of squares: {}", sum);
+    Ok(())
+}
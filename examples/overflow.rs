@@ -0,0 +1,21 @@
+//! Following code was written intentionally to overflow a fiber stack.
+
+use fib::task;
+
+fn recurse(depth: usize) -> usize {
+    if depth == usize::MAX {
+        return 0;
+    }
+    let buf = [depth as u8; 512];
+    std::hint::black_box(&buf);
+    recurse(depth + 1) + buf[0] as usize
+}
+
+#[fib::main]
+fn main() {
+    let handle = task::Builder::new()
+        .name("recursive".to_string())
+        .spawn(|| recurse(0));
+    handle.join();
+    unreachable!();
+}
@@ -1,4 +1,4 @@
-/// Following code was written intentionally to cause a reborrow error.
+//! Following code was written intentionally to cause a reborrow error.
 
 use std::{cell::RefCell, rc::Rc};
 use fib::task;
@@ -1,10 +1,17 @@
 use proc_macro::TokenStream;
-use quote::quote;
-use syn::{parse_macro_input, ItemFn};
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{quote, quote_spanned};
+use syn::{parse::Parser, parse_macro_input, punctuated::Punctuated, spanned::Spanned, Expr, ExprLit, ItemFn, Lit, Meta, ReturnType, Token};
 
 
-pub(crate) fn main_impl(item: TokenStream) -> TokenStream {
+pub(crate) fn main_impl(args: TokenStream, item: TokenStream) -> TokenStream {
     let input = parse_macro_input!(item as ItemFn);
+    let (builder, warnings) = match Parser::parse(Punctuated::<Meta, Token![,]>::parse_terminated, args)
+        .and_then(|args| builder_calls(&args, &["stack_size", "flavor", "workers", "max_tasks"]))
+    {
+        Ok(builder) => builder,
+        Err(err) => return err.to_compile_error().into(),
+    };
 
     let ItemFn {
         attrs,
@@ -13,18 +20,134 @@ pub(crate) fn main_impl(item: TokenStream) -> TokenStream {
         block,
     } = input;
 
-    let stmts = block.stmts;
-    let ident = sig.ident.clone();
+    let ret = return_type(&sig.output);
 
+    quote! {
+        #(#attrs)*
+        #vis #sig {
+            #warnings
+            let mut __rt = fib::runtime::Builder::new()
+                #builder
+                .build();
+            __rt.block_on(move || -> #ret #block)
+        }
+    }.into()
+}
+
+/// Runs the test on a runtime of its own, which is dropped before a failure is propagated,
+/// so that no fiber outlives the test.
+pub(crate) fn test_impl(args: TokenStream, item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as ItemFn);
+    let (builder, _) = match Parser::parse(Punctuated::<Meta, Token![,]>::parse_terminated, args)
+        .and_then(|args| builder_calls(&args, &["timeout", "seed", "stack_size", "paused_time"]))
+    {
+        Ok(builder) => builder,
+        Err(err) => return err.to_compile_error().into(),
+    };
+
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = input;
+
+    let ret = return_type(&sig.output);
 
     quote! {
+        #[::core::prelude::v1::test]
         #(#attrs)*
         #vis #sig {
-            let __rt = fib::runtime::runtime();
-            let __result = __rt.block_on(|| {
-                #(#stmts)*
-            });
-            __result
+            let mut __rt = fib::runtime::Builder::new()
+                #builder
+                .build();
+            let __result = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+                __rt.block_on(move || -> #ret #block)
+            }));
+            drop(__rt);
+            match __result {
+                Ok(__result) => __result,
+                Err(__payload) => ::std::panic::resume_unwind(__payload),
+            }
         }
     }.into()
-}
\ No newline at end of file
+}
+
+fn return_type(output: &ReturnType) -> TokenStream2 {
+    match output {
+        ReturnType::Default => quote! { () },
+        ReturnType::Type(_, ty) => quote! { #ty },
+    }
+}
+
+/// Translate `key = value` and flag arguments into calls on `fib::runtime::Builder`,
+/// along with statements raising warnings about arguments which are accepted but have no effect.
+fn builder_calls(args: &Punctuated<Meta, Token![,]>, allowed: &[&str]) -> syn::Result<(TokenStream2, TokenStream2)> {
+    let mut calls = TokenStream2::new();
+    let mut warnings = TokenStream2::new();
+    for arg in args {
+        let key = arg.path().get_ident().map(ToString::to_string).unwrap_or_default();
+        if !allowed.contains(&key.as_str()) {
+            let msg = format!("unknown argument, expected one of: {}", allowed.join(", "));
+            return Err(syn::Error::new(arg.span(), msg));
+        }
+        let call = match (key.as_str(), arg) {
+            ("paused_time", Meta::Path(_)) => quote! { .paused_time(true) },
+            ("timeout", Meta::NameValue(arg)) => {
+                let millis = duration_millis(&arg.value)?;
+                quote! { .timeout(::std::time::Duration::from_millis(#millis)) }
+            },
+            ("flavor", Meta::NameValue(arg)) => match &arg.value {
+                Expr::Lit(ExprLit { lit: Lit::Str(lit), .. }) if lit.value() == "current_thread" => quote! {},
+                Expr::Lit(ExprLit { lit: Lit::Str(lit), .. }) if lit.value() == "multi_thread" => {
+                    warnings.extend(warning(arg.value.span(),
+                        "fib runs all fibers on the calling thread (M:1), `flavor = \"multi_thread\"` runs them on a single thread"));
+                    quote! {}
+                },
+                value => return Err(syn::Error::new(value.span(), "expected \"current_thread\" or \"multi_thread\"")),
+            },
+            ("workers", Meta::NameValue(arg)) => {
+                warnings.extend(warning(arg.span(),
+                    "fib runs all fibers on the calling thread (M:1), `workers` has no effect"));
+                quote! {}
+            },
+            ("seed" | "stack_size" | "max_tasks", Meta::NameValue(arg)) => {
+                let method = arg.path.get_ident().unwrap();
+                let value = &arg.value;
+                quote! { .#method(#value) }
+            },
+            _ => return Err(syn::Error::new(arg.span(), format!("malformed `{}` argument", key))),
+        };
+        calls.extend(call);
+    }
+    Ok((calls, warnings))
+}
+
+/// A statement using a deprecated item, as stable proc macros cannot emit warnings of their own.
+fn warning(span: proc_macro2::Span, note: &str) -> TokenStream2 {
+    quote_spanned! {span=>
+        {
+            #[deprecated(note = #note)]
+            struct FibIgnoredArgument;
+            let _ = FibIgnoredArgument;
+        }
+    }
+}
+
+/// Parse durations like `"500ms"`, `"5s"` or `"2m"`.
+fn duration_millis(expr: &Expr) -> syn::Result<u64> {
+    let err = || syn::Error::new(expr.span(), "expected a duration like \"500ms\", \"5s\" or \"2m\"");
+    let Expr::Lit(ExprLit { lit: Lit::Str(lit), .. }) = expr else {
+        return Err(err());
+    };
+    let value = lit.value();
+    let split = value.find(|c: char| !c.is_ascii_digit()).ok_or_else(err)?;
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number.parse().map_err(|_| err())?;
+    match unit {
+        "ms" => Ok(number),
+        "s" => Ok(number * 1000),
+        "m" => Ok(number * 60 * 1000),
+        _ => Err(err()),
+    }
+}
@@ -7,8 +7,77 @@ extern crate proc_macro;
 use proc_macro::TokenStream;
 
 mod entry;
+mod select;
+mod yield_safe;
 
+/// Run `main` as the root fiber of a new runtime. The return type is kept as is,
+/// so `main` may return e.g. `ExitCode` or `Result<(), E>` and use `?`.
+///
+/// Arguments: `stack_size = 65536`, `max_tasks = 1024` to bound the number of live tasks,
+/// and `flavor = "current_thread"`, the only flavor of an M:1 runtime.
+/// `flavor = "multi_thread"` and `workers = 4` are accepted for compatibility with tokio,
+/// but still run every fiber on the calling thread, which they warn about.
 #[proc_macro_attribute]
-pub fn main(_args: TokenStream, item: TokenStream) -> TokenStream {
-    entry::main_impl(item)
+pub fn main(args: TokenStream, item: TokenStream) -> TokenStream {
+    entry::main_impl(args, item)
+}
+
+/// Run a test on a fresh runtime, which is torn down before the test returns.
+/// Deadlocks and timeouts fail the test with a dump of the tasks.
+///
+/// Arguments: `timeout = "5s"` (also `"ms"` and `"m"`), `seed = 42` to schedule
+/// tasks pseudo-randomly, `stack_size = 65536` and `paused_time` to start with a paused clock.
+#[proc_macro_attribute]
+pub fn test(args: TokenStream, item: TokenStream) -> TokenStream {
+    entry::test_impl(args, item)
+}
+
+/// Wait for whichever branch becomes ready first:
+///
+/// ```ignore
+/// fib::select! {
+///     msg = &rx => println!("{:?}", msg),
+///     () = &shutdown => return,
+///     () = fib::time::Sleep::new(Duration::from_secs(1)) => println!("timeout"),
+/// }
+/// ```
+///
+/// Each source must implement `fib::select::Selectable`. Branches are tried starting from
+/// a rotating position, or in order after `biased;`. A `default => ..` branch runs
+/// if no other branch is ready, instead of blocking. Patterns must be irrefutable.
+#[proc_macro]
+pub fn select(input: TokenStream) -> TokenStream {
+    select::select_impl(input)
+}
+
+/// Reject `RefCell` borrows, `std::sync` guards and `Cell` references
+/// which are still alive across calls that may suspend the fiber,
+/// such as `yield_now`, `lock`, `read`, `write`, `recv`, `join` or `sleep`.
+/// The analysis is purely syntactic: it works on names, not types,
+/// and does not look into closures, which may run in another fiber.
+///
+/// ```compile_fail
+/// use std::cell::RefCell;
+///
+/// #[fib::yield_safe]
+/// fn add(total: &RefCell<i32>, lock: &fib::sync::RwLock<i32>) {
+///     let mut total = total.borrow_mut();
+///     *total += *lock.read();
+/// }
+/// ```
+///
+/// Taking the read lock first, or dropping the borrow before it, is fine:
+///
+/// ```
+/// use std::cell::RefCell;
+///
+/// #[fib::yield_safe]
+/// fn add(total: &RefCell<i32>, lock: &fib::sync::RwLock<i32>) {
+///     let value = *lock.read();
+///     *total.borrow_mut() += value;
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn yield_safe(_args: TokenStream, item: TokenStream) -> TokenStream {
+    yield_safe::yield_safe_impl(item)
 }
\ No newline at end of file
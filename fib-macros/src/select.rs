@@ -0,0 +1,127 @@
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::{format_ident, quote};
+use syn::{parse::{Parse, ParseStream}, parse_macro_input, token, Expr, Ident, Pat, Token};
+
+struct Select {
+    biased: bool,
+    branches: Vec<Branch>,
+    default: Option<Expr>,
+}
+
+/// `<pat> = <selectable> => <body>`
+struct Branch {
+    pat: Pat,
+    source: Expr,
+    body: Expr,
+}
+
+impl Parse for Select {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let biased = input.peek(Ident) && input.peek2(Token![;]) && input.fork().parse::<Ident>()? == "biased";
+        if biased {
+            input.parse::<Ident>()?;
+            input.parse::<Token![;]>()?;
+        }
+
+        let mut branches = vec![];
+        let mut default = None;
+        while !input.is_empty() {
+            if input.peek(Token![default]) {
+                let span = input.parse::<Token![default]>()?.span;
+                input.parse::<Token![=>]>()?;
+                if default.replace(parse_body(input)?).is_some() {
+                    return Err(syn::Error::new(span, "duplicate `default` branch"));
+                }
+            } else {
+                let pat = Pat::parse_single(input)?;
+                input.parse::<Token![=]>()?;
+                let source = input.parse()?;
+                input.parse::<Token![=>]>()?;
+                let body = parse_body(input)?;
+                branches.push(Branch { pat, source, body });
+            }
+        }
+        if branches.is_empty() {
+            return Err(syn::Error::new(Span::call_site(), "select! needs at least one branch besides `default`"));
+        }
+        Ok(Self { biased, branches, default })
+    }
+}
+
+/// A block, or an expression followed by a comma unless it is the last branch.
+fn parse_body(input: ParseStream) -> syn::Result<Expr> {
+    if input.peek(token::Brace) {
+        let body = Expr::Block(input.parse()?);
+        input.parse::<Option<Token![,]>>()?;
+        return Ok(body);
+    }
+    let body = input.parse()?;
+    if !input.is_empty() {
+        input.parse::<Token![,]>()?;
+    }
+    Ok(body)
+}
+
+pub(crate) fn select_impl(input: TokenStream) -> TokenStream {
+    let Select { biased, branches, default } = parse_macro_input!(input as Select);
+
+    let n = branches.len();
+    let indices: Vec<usize> = (0..n).collect();
+    let sources: Vec<_> = (0..n).map(|i| format_ident!("__fib_select_{}", i)).collect();
+    let variants: Vec<_> = (0..n).map(|i| format_ident!("_{}", i)).collect();
+    let generics: Vec<_> = (0..n).map(|i| format_ident!("__T{}", i)).collect();
+    let exprs = branches.iter().map(|branch| &branch.source);
+    let pats = branches.iter().map(|branch| &branch.pat);
+    let bodies = branches.iter().map(|branch| &branch.body);
+
+    let deregister = quote! {
+        #( fib::select::Selectable::deregister(&mut #sources); )*
+    };
+    let (default_variant, wait, default_arm) = match default {
+        Some(default) => (
+            quote! { __Default, },
+            quote! { break 'fib_select __FibSelect::__Default; },
+            quote! { __FibSelect::__Default => #default, },
+        ),
+        None => (
+            quote! {},
+            quote! {
+                #( fib::select::Selectable::register(&mut #sources); )*
+                if let Err(__fib_select_payload) = fib::select::__block(#n) {
+                    #deregister
+                    ::std::panic::resume_unwind(__fib_select_payload);
+                }
+            },
+            quote! {},
+        ),
+    };
+
+    quote! {
+        {
+            enum __FibSelect<#(#generics),*> {
+                #( #variants(#generics), )*
+                #default_variant
+            }
+            #( let mut #sources = #exprs; )*
+            let __fib_select_out = 'fib_select: loop {
+                for __fib_select_i in fib::select::__order(#n, #biased) {
+                    match __fib_select_i {
+                        #(
+                            #indices => if let Some(__fib_select_value) = fib::select::Selectable::try_select(&mut #sources) {
+                                break 'fib_select __FibSelect::#variants(__fib_select_value);
+                            },
+                        )*
+                        _ => unreachable!(),
+                    }
+                }
+                #wait
+            };
+            #deregister
+            match __fib_select_out {
+                #( __FibSelect::#variants(#pats) => #bodies, )*
+                #default_arm
+            }
+        }
+    }.into()
+}
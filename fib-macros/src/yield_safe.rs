@@ -0,0 +1,229 @@
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{parse_macro_input, spanned::Spanned, visit::{self, Visit}, Expr, ExprCall, ExprMethodCall, ItemFn, Local, Pat};
+
+/// Free functions which may suspend the calling fiber.
+const YIELDING_FNS: &[&str] = &["yield_now", "sleep", "wait"];
+/// Methods of fib primitives which may suspend the calling fiber.
+const YIELDING_METHODS: &[&str] = &["lock", "recv", "blocking_recv", "join", "wait", "acquire", "sleep"];
+/// Methods of fib's `RwLock`, told apart from `io::Read::read` and `io::Write::write`
+/// by taking no arguments.
+const YIELDING_RWLOCK_METHODS: &[&str] = &["read", "write"];
+/// Methods of `std::sync` locks, which return a `Result` wrapping the guard.
+const STD_LOCK_METHODS: &[&str] = &["lock", "read", "write", "try_lock", "try_read", "try_write"];
+
+pub(crate) fn yield_safe_impl(item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as ItemFn);
+
+    let errors = check(&input);
+    let errors = errors.iter().map(syn::Error::to_compile_error);
+
+    quote! {
+        #(#errors)*
+        #input
+    }.into()
+}
+
+/// A guard-like binding which must not be alive across a yield point.
+struct Live {
+    name: String,
+    kind: &'static str,
+    span: Span,
+}
+
+#[derive(Default)]
+struct Checker {
+    live: Vec<Live>,
+    errors: Vec<syn::Error>,
+}
+
+pub(crate) fn check(input: &ItemFn) -> Vec<syn::Error> {
+    let mut checker = Checker::default();
+    checker.visit_block(&input.block);
+    checker.errors
+}
+
+impl Checker {
+    fn yield_point(&mut self, name: &str, span: Span) {
+        for live in &self.live {
+            let mut error = syn::Error::new(span, format!(
+                "`{}` may suspend the fiber while the {} `{}` is still alive",
+                name, live.kind, live.name,
+            ));
+            error.combine(syn::Error::new(live.span, format!(
+                "`{}` is taken here and held across the yield point, drop it before yielding",
+                live.name,
+            )));
+            self.errors.push(error);
+        }
+    }
+}
+
+impl<'ast> Visit<'ast> for Checker {
+    fn visit_block(&mut self, block: &'ast syn::Block) {
+        let outer = self.live.len();
+        visit::visit_block(self, block);
+        self.live.truncate(outer);
+    }
+
+    fn visit_local(&mut self, local: &'ast Local) {
+        visit::visit_local(self, local);
+        let Some(init) = &local.init else {
+            return;
+        };
+        let Some(kind) = guard_kind(&init.expr) else {
+            return;
+        };
+        if let Some(name) = binding_name(&local.pat) {
+            self.live.push(Live { name, kind, span: init.expr.span() });
+        }
+    }
+
+    fn visit_expr_call(&mut self, call: &'ast ExprCall) {
+        visit::visit_expr_call(self, call);
+        let Expr::Path(func) = &*call.func else {
+            return;
+        };
+        let Some(last) = func.path.segments.last() else {
+            return;
+        };
+        let name = last.ident.to_string();
+        if name == "drop" {
+            // `drop(guard)` ends the guard's life early.
+            if let Some(Expr::Path(arg)) = call.args.first()
+                && let Some(arg) = arg.path.get_ident()
+                && let Some(i) = self.live.iter().rposition(|live| arg == &live.name)
+            {
+                self.live.remove(i);
+            }
+        } else if YIELDING_FNS.contains(&name.as_str()) {
+            self.yield_point(&name, call.span());
+        }
+    }
+
+    fn visit_expr_method_call(&mut self, call: &'ast ExprMethodCall) {
+        if is_std_lock(call) {
+            // `std_mutex.lock().unwrap()` does not yield, skip the inner `lock`.
+            let Expr::MethodCall(lock) = &*call.receiver else { unreachable!() };
+            self.visit_expr(&lock.receiver);
+            return;
+        }
+        visit::visit_expr_method_call(self, call);
+        let name = call.method.to_string();
+        if YIELDING_METHODS.contains(&name.as_str())
+            || YIELDING_RWLOCK_METHODS.contains(&name.as_str()) && call.args.is_empty()
+        {
+            self.yield_point(&name, call.method.span());
+        }
+    }
+
+    // Closures and nested items run at some other time, possibly in another fiber.
+    fn visit_expr_closure(&mut self, _: &'ast syn::ExprClosure) {}
+    fn visit_item(&mut self, _: &'ast syn::Item) {}
+}
+
+/// What kind of guard the expression produces, if any.
+fn guard_kind(expr: &Expr) -> Option<&'static str> {
+    match expr {
+        Expr::Paren(e) => guard_kind(&e.expr),
+        Expr::Try(e) => guard_kind(&e.expr),
+        Expr::Reference(e) => match &*e.expr {
+            // `&mut *cell.as_ptr()`
+            Expr::Unary(syn::ExprUnary { op: syn::UnOp::Deref(_), expr, .. }) => match &**expr {
+                Expr::MethodCall(call) if call.method == "as_ptr" => Some("`Cell` reference"),
+                _ => None,
+            },
+            expr => guard_kind(expr),
+        },
+        Expr::Unsafe(e) => match e.block.stmts.as_slice() {
+            [syn::Stmt::Expr(expr, None)] => guard_kind(expr),
+            _ => None,
+        },
+        Expr::MethodCall(call) => match call.method.to_string().as_str() {
+            "borrow" | "borrow_mut" => Some("`RefCell` borrow"),
+            "unwrap" | "expect" if is_std_lock(call) => Some("`std::sync` guard"),
+            "unwrap" | "expect" => match &*call.receiver {
+                Expr::MethodCall(inner) if inner.method == "try_borrow" || inner.method == "try_borrow_mut" => {
+                    Some("`RefCell` borrow")
+                },
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// `lock.lock().unwrap()` and friends.
+fn is_std_lock(call: &ExprMethodCall) -> bool {
+    (call.method == "unwrap" || call.method == "expect")
+        && matches!(&*call.receiver, Expr::MethodCall(inner)
+            if STD_LOCK_METHODS.contains(&inner.method.to_string().as_str()))
+}
+
+fn binding_name(pat: &Pat) -> Option<String> {
+    match pat {
+        Pat::Ident(ident) => Some(ident.ident.to_string()),
+        Pat::Type(pat) => binding_name(&pat.pat),
+        // `let _ = ..` drops the value right away.
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use syn::parse_quote;
+
+    use super::*;
+
+    #[test]
+    fn test_borrow_across_yield() {
+        let input: ItemFn = parse_quote! {
+            fn f(cell: &RefCell<i32>, m: &std::sync::Mutex<i32>) {
+                let a = cell.borrow_mut();
+                let _ = cell.borrow();
+                let g = m.lock().unwrap();
+                drop(g);
+                if true {
+                    task::yield_now();
+                }
+            }
+        };
+        let errors = check(&input);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].to_string().contains("`yield_now` may suspend the fiber while the `RefCell` borrow `a`"));
+    }
+
+    #[test]
+    fn test_scoped_borrow() {
+        let input: ItemFn = parse_quote! {
+            fn f(cell: &RefCell<i32>, mutex: &fib::sync::Mutex<i32>) {
+                {
+                    let mut a = cell.borrow_mut();
+                    *a += 1;
+                }
+                let guard = mutex.lock();
+                handle.join();
+                task::spawn(move || {
+                    let b = cell.borrow();
+                });
+            }
+        };
+        assert!(check(&input).is_empty());
+    }
+
+    #[test]
+    fn test_rwlock() {
+        let input: ItemFn = parse_quote! {
+            fn f(cell: &RefCell<Vec<u8>>, lock: &fib::sync::RwLock<i32>, file: &mut File) {
+                let buf = cell.borrow();
+                file.write(&buf).unwrap();
+                let n = *lock.read();
+            }
+        };
+        let errors = check(&input);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].to_string().contains("`read` may suspend the fiber while the `RefCell` borrow `buf`"));
+    }
+}
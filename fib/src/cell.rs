@@ -0,0 +1,241 @@
+//! Cells for sharing data between fibers.
+//! A `RefCell` borrow held across a yield point silently becomes visible to every other fiber,
+//! so the borrow check fails far away from the actual mistake (see `examples/reborrow.rs`).
+//! `FiberRefCell` records which fiber holds each borrow and where it was taken,
+//! and panics as soon as the holder suspends with the borrow still alive.
+
+use std::{cell::{Cell, RefCell, UnsafeCell}, fmt::Debug, ops::{Deref, DerefMut}, panic::Location};
+
+use crate::runtime::runtime;
+
+struct Borrow {
+    token: u64,
+    cell: usize,
+    task: usize,
+    site: &'static Location<'static>,
+    mutable: bool,
+}
+
+thread_local! {
+    /// Every outstanding `FiberRefCell` borrow on this thread.
+    static BORROWS: RefCell<Vec<Borrow>> = const { RefCell::new(Vec::new()) };
+    static NEXT_TOKEN: Cell<u64> = const { Cell::new(0) };
+}
+
+/// A `RefCell` which is aware of fibers.
+/// Holding a borrow while the holder suspends (e.g. `task::yield_now`, `Mutex::lock`
+/// or any other blocking call) panics, naming the holder fiber, the borrow site and the yield site.
+pub struct FiberRefCell<T: ?Sized> {
+    /// Same encoding as `RefCell`: positive for shared borrows, -1 for a mutable one.
+    borrow: Cell<isize>,
+    value: UnsafeCell<T>,
+}
+
+pub struct FiberRef<'a, T: ?Sized> {
+    cell: &'a FiberRefCell<T>,
+    token: u64,
+}
+
+pub struct FiberRefMut<'a, T: ?Sized> {
+    cell: &'a FiberRefCell<T>,
+    token: u64,
+}
+
+impl<T> FiberRefCell<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            borrow: Cell::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> FiberRefCell<T> {
+    #[track_caller]
+    pub fn borrow(&self) -> FiberRef<'_, T> {
+        if self.borrow.get() < 0 {
+            self.conflict();
+        }
+        self.borrow.set(self.borrow.get() + 1);
+        FiberRef { cell: self, token: self.register(false) }
+    }
+
+    #[track_caller]
+    pub fn borrow_mut(&self) -> FiberRefMut<'_, T> {
+        if self.borrow.get() != 0 {
+            self.conflict();
+        }
+        self.borrow.set(-1);
+        FiberRefMut { cell: self, token: self.register(true) }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    fn addr(&self) -> usize {
+        self as *const Self as *const () as usize
+    }
+
+    #[track_caller]
+    fn register(&self, mutable: bool) -> u64 {
+        let token = NEXT_TOKEN.replace(NEXT_TOKEN.get() + 1);
+        let borrow = Borrow {
+            token,
+            cell: self.addr(),
+            task: runtime().cur_task(),
+            site: Location::caller(),
+            mutable,
+        };
+        BORROWS.with(|borrows| borrows.borrow_mut().push(borrow));
+        token
+    }
+
+    #[track_caller]
+    fn conflict(&self) -> ! {
+        let rt = runtime();
+        let holder = BORROWS.with(|borrows| {
+            borrows.borrow().iter()
+                .find(|borrow| borrow.cell == self.addr() && (borrow.mutable || self.borrow.get() > 0))
+                .map(|borrow| (borrow.task, borrow.site, borrow.mutable))
+        });
+        let (task, site, mutable) = holder.expect("FiberRefCell borrow is not registered");
+        let holder = if task == rt.cur_task() {
+            "this fiber".to_string()
+        } else {
+            format!("fiber {}", rt.task_label(task))
+        };
+        panic!(
+            "FiberRefCell already {} by {} at {}",
+            if mutable { "mutably borrowed" } else { "borrowed" },
+            holder,
+            site,
+        );
+    }
+}
+
+fn unregister(token: u64) {
+    BORROWS.with(|borrows| borrows.borrow_mut().retain(|borrow| borrow.token != token));
+}
+
+/// Whether the given task holds any `FiberRefCell` borrow.
+pub(crate) fn holds_borrow(task: usize) -> bool {
+    BORROWS.with(|borrows| borrows.borrow().iter().any(|borrow| borrow.task == task))
+}
+
+/// Called right before the current fiber suspends, the caller location being the yield site.
+#[track_caller]
+pub(crate) fn check_suspend() {
+    let rt = runtime();
+    let task = rt.cur_task();
+    let site = BORROWS.with(|borrows| {
+        borrows.borrow().iter()
+            .find(|borrow| borrow.task == task)
+            .map(|borrow| borrow.site)
+    });
+    if let Some(site) = site {
+        panic!(
+            "fiber {} suspended at {} while holding a FiberRefCell borrow taken at {}",
+            rt.task_label(task),
+            Location::caller(),
+            site,
+        );
+    }
+}
+
+impl<T: Default> Default for FiberRefCell<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized + Debug> Debug for FiberRefCell<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("FiberRefCell");
+        if self.borrow.get() < 0 {
+            d.field("value", &format_args!("<borrowed>"));
+        } else {
+            d.field("value", &unsafe { &*self.value.get() });
+        }
+        d.finish()
+    }
+}
+
+impl<T: ?Sized> Deref for FiberRef<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.cell.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for FiberRef<'_, T> {
+    fn drop(&mut self) {
+        self.cell.borrow.set(self.cell.borrow.get() - 1);
+        unregister(self.token);
+    }
+}
+
+impl<T: ?Sized> Deref for FiberRefMut<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.cell.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for FiberRefMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.cell.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for FiberRefMut<'_, T> {
+    fn drop(&mut self) {
+        self.cell.borrow.set(0);
+        unregister(self.token);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::{runtime::runtime, task};
+
+    use super::*;
+
+    #[test]
+    fn test_borrow_released_before_yield() {
+        runtime().block_on(|| {
+            let cell = Rc::new(FiberRefCell::new(0));
+            let handle = task::spawn({
+                let cell = cell.clone();
+                move || {
+                    *cell.borrow_mut() += 1;
+                    task::yield_now();
+                    *cell.borrow_mut() += 1;
+                }
+            });
+            *cell.borrow_mut() += 1;
+            handle.join();
+            assert_eq!(*cell.borrow(), 3);
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "fiber 1/holder suspended at")]
+    fn test_borrow_held_across_yield() {
+        runtime().block_on(|| {
+            let cell = Rc::new(FiberRefCell::new(0));
+            task::Builder::new().name("holder".to_string()).spawn(move || {
+                let _a = cell.borrow_mut();
+                task::yield_now();
+            }).join();
+        });
+    }
+}
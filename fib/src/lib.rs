@@ -7,5 +7,16 @@ mod config;
 pub mod task;
 pub mod sync;
 pub mod runtime;
+pub mod cell;
+pub mod model;
+pub mod time;
+pub mod select;
+pub mod signal;
+pub mod metrics;
+#[cfg(all(feature = "profile", target_os = "linux"))]
+pub mod profile;
 
-pub use fib_macros::main;
\ No newline at end of file
+// Lets `fib::` paths emitted by our macros resolve inside this crate too.
+extern crate self as fib;
+
+pub use fib_macros::{main, select, test, yield_safe};
\ No newline at end of file
@@ -0,0 +1,107 @@
+//! Metrics of the runtime and of named sync primitives, see `prometheus` for exporting them.
+//!
+//! Primitives are only instrumented once they are named, e.g. `Mutex::new(db).named("db")`,
+//! so unnamed ones cost nothing.
+
+use std::{cell::{Cell, RefCell}, rc::{Rc, Weak}, time::{Duration, Instant}};
+
+pub mod prometheus;
+
+pub use crate::runtime::{CpuUsage, RuntimeMetrics, TaskMetrics};
+
+/// Upper bounds of the wait time histogram buckets, in seconds.
+pub const WAIT_BUCKETS: [f64; 7] = [0.0001, 0.001, 0.01, 0.1, 1.0, 10.0, f64::INFINITY];
+
+thread_local! {
+    static REGISTRY: RefCell<Vec<Weak<Stats>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Snapshot of the statistics of a named primitive.
+#[derive(Debug, Clone)]
+pub struct PrimitiveMetrics {
+    /// `"mutex"`, `"semaphore"` or `"channel"`.
+    pub kind: &'static str,
+    pub name: String,
+    /// Times a task had to block on the primitive.
+    pub contentions: u64,
+    /// Number of waits per bucket of `WAIT_BUCKETS`, not cumulative.
+    pub wait_buckets: [u64; WAIT_BUCKETS.len()],
+    pub wait_total: Duration,
+    /// Blocked tasks for locks and semaphores, buffered messages for channels.
+    pub queue_len: usize,
+}
+
+/// The named primitives of this thread which are still alive, in the order they were named.
+pub fn primitives() -> Vec<PrimitiveMetrics> {
+    REGISTRY.with_borrow_mut(|registry| {
+        registry.retain(|stats| stats.strong_count() > 0);
+        registry.iter()
+            .filter_map(Weak::upgrade)
+            .map(|stats| PrimitiveMetrics {
+                kind: stats.kind,
+                name: stats.name.clone(),
+                contentions: stats.contentions.get(),
+                wait_buckets: stats.wait_buckets.get(),
+                wait_total: stats.wait_total.get(),
+                queue_len: stats.queue_len.get(),
+            })
+            .collect()
+    })
+}
+
+struct Stats {
+    kind: &'static str,
+    name: String,
+    contentions: Cell<u64>,
+    wait_buckets: Cell<[u64; WAIT_BUCKETS.len()]>,
+    wait_total: Cell<Duration>,
+    queue_len: Cell<usize>,
+}
+
+/// Statistics hook embedded in the primitives, a no-op until the primitive is named.
+#[derive(Default)]
+pub(crate) struct Instrument(Option<Rc<Stats>>);
+
+impl Instrument {
+    pub(crate) const fn none() -> Self {
+        Self(None)
+    }
+
+    pub(crate) fn named(kind: &'static str, name: String) -> Self {
+        let stats = Rc::new(Stats {
+            kind,
+            name,
+            contentions: Cell::new(0),
+            wait_buckets: Cell::new([0; WAIT_BUCKETS.len()]),
+            wait_total: Cell::new(Duration::ZERO),
+            queue_len: Cell::new(0),
+        });
+        REGISTRY.with_borrow_mut(|registry| registry.push(Rc::downgrade(&stats)));
+        Self(Some(stats))
+    }
+
+    /// The current task is about to block. Returns the start of the wait, for `waited`.
+    pub(crate) fn contended(&self) -> Option<Instant> {
+        let stats = self.0.as_ref()?;
+        stats.contentions.set(stats.contentions.get() + 1);
+        Some(crate::time::now())
+    }
+
+    pub(crate) fn waited(&self, start: Option<Instant>) {
+        let (Some(stats), Some(start)) = (&self.0, start) else {
+            return;
+        };
+        let waited = crate::time::now() - start;
+        let bucket = WAIT_BUCKETS.iter().position(|&le| waited.as_secs_f64() <= le).unwrap();
+        let mut buckets = stats.wait_buckets.get();
+        buckets[bucket] += 1;
+        stats.wait_buckets.set(buckets);
+        stats.wait_total.set(stats.wait_total.get() + waited);
+    }
+
+    pub(crate) fn set_queue_len(&self, len: usize) {
+        if let Some(stats) = &self.0 {
+            stats.queue_len.set(len);
+        }
+    }
+}
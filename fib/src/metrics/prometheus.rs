@@ -0,0 +1,235 @@
+//! Prometheus text format exporter.
+
+use std::{collections::BTreeMap, fmt::Write as _, io::{self, ErrorKind, Read, Write}, net::{TcpListener, TcpStream}, time::{Duration, Instant}};
+
+use crate::{metrics::{self, WAIT_BUCKETS}, runtime::runtime, task::{self, BlockCause, JoinHandle}, time};
+
+/// How often the endpoint polls its sockets, as there is no I/O reactor yet.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How long a client may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Render the metrics of the current runtime and of the named primitives of this thread
+/// in the Prometheus text exposition format.
+/// Tasks are aggregated by name, as one series per task id would grow without bound;
+/// `Runtime::dump` has the details of every task.
+pub fn render() -> String {
+    let rt = runtime().metrics();
+    let mut out = String::new();
+
+    counter(&mut out, "fib_tasks_spawned_total", "Tasks spawned.", rt.spawned);
+    counter(&mut out, "fib_tasks_completed_total", "Tasks which have finished.", rt.completed);
+    counter(&mut out, "fib_yields_total", "Times a task yielded and stayed runnable.", rt.yields);
+    counter(&mut out, "fib_context_switches_total", "Times the scheduler switched into a task.", rt.context_switches);
+    counter(&mut out, "fib_wakeups_total", "Times a blocked task was made runnable again.", rt.wakeups);
+    counter(&mut out, "fib_stalls_total", "Times a task ran longer than the watchdog threshold without yielding.", rt.stalls);
+
+    header(&mut out, "fib_blocks_total", "Times a task blocked, by cause.", "counter");
+    for cause in BlockCause::ALL {
+        let cause_label = format!("{:?}", cause).to_lowercase();
+        writeln!(out, "fib_blocks_total{{cause=\"{}\"}} {}", cause_label, rt.blocks(cause)).unwrap();
+    }
+
+    gauge(&mut out, "fib_running_tasks", "Runnable tasks waiting for their turn.", rt.running_tasks);
+    gauge(&mut out, "fib_running_tasks_peak", "Peak number of runnable tasks.", rt.peak_running_tasks);
+    gauge(&mut out, "fib_blocking_tasks", "Blocked tasks.", rt.blocking_tasks);
+    gauge(&mut out, "fib_blocking_tasks_peak", "Peak number of blocked tasks.", rt.peak_blocking_tasks);
+
+    // Live tasks come and go, so their sums are gauges.
+    let mut by_name: BTreeMap<&str, (usize, u64, Duration)> = BTreeMap::new();
+    for task in &rt.tasks {
+        let entry = by_name.entry(task.name.as_deref().unwrap_or("")).or_default();
+        entry.0 += 1;
+        entry.1 += task.polls;
+        entry.2 += task.busy;
+    }
+    header(&mut out, "fib_tasks", "Live tasks, by name.", "gauge");
+    for (name, (tasks, _, _)) in &by_name {
+        writeln!(out, "fib_tasks{{name=\"{}\"}} {}", escape(name), tasks).unwrap();
+    }
+    header(&mut out, "fib_task_polls", "Times the live tasks of a name were switched into.", "gauge");
+    for (name, (_, polls, _)) in &by_name {
+        writeln!(out, "fib_task_polls{{name=\"{}\"}} {}", escape(name), polls).unwrap();
+    }
+    header(&mut out, "fib_task_busy_seconds", "Time the live tasks of a name have spent running.", "gauge");
+    for (name, (_, _, busy)) in &by_name {
+        writeln!(out, "fib_task_busy_seconds{{name=\"{}\"}} {}", escape(name), busy.as_secs_f64()).unwrap();
+    }
+    header(&mut out, "fib_task_cpu_seconds_total", "Thread CPU time spent by the tasks of a name, finished or alive, if tracked.", "counter");
+    for usage in runtime().cpu_report() {
+        let name = usage.name.as_deref().unwrap_or("");
+        writeln!(out, "fib_task_cpu_seconds_total{{name=\"{}\"}} {}", escape(name), usage.cpu_time.as_secs_f64()).unwrap();
+    }
+
+    let primitives = metrics::primitives();
+    header(&mut out, "fib_primitive_contentions_total", "Times a task had to block on a named primitive.", "counter");
+    for primitive in &primitives {
+        writeln!(out, "fib_primitive_contentions_total{{{}}} {}", primitive_labels(primitive), primitive.contentions).unwrap();
+    }
+    header(&mut out, "fib_primitive_wait_seconds", "Time tasks spent blocked on a named primitive.", "histogram");
+    for primitive in &primitives {
+        let labels = primitive_labels(primitive);
+        let mut cumulative = 0;
+        for (le, count) in WAIT_BUCKETS.iter().zip(primitive.wait_buckets) {
+            cumulative += count;
+            let le = if le.is_infinite() { "+Inf".to_string() } else { le.to_string() };
+            writeln!(out, "fib_primitive_wait_seconds_bucket{{{},le=\"{}\"}} {}", labels, le, cumulative).unwrap();
+        }
+        writeln!(out, "fib_primitive_wait_seconds_sum{{{}}} {}", labels, primitive.wait_total.as_secs_f64()).unwrap();
+        writeln!(out, "fib_primitive_wait_seconds_count{{{}}} {}", labels, cumulative).unwrap();
+    }
+    header(&mut out, "fib_primitive_queue_length", "Tasks blocked on a named lock or semaphore, or messages buffered in a named channel.", "gauge");
+    for primitive in &primitives {
+        writeln!(out, "fib_primitive_queue_length{{{}}} {}", primitive_labels(primitive), primitive.queue_len).unwrap();
+    }
+    out
+}
+
+/// Serve `render()` over HTTP on `GET /metrics` from a fiber of the current runtime,
+/// until the returned task is cancelled.
+/// The sockets are polled every few milliseconds, as the runtime has no I/O reactor yet.
+pub fn serve(listener: TcpListener) -> io::Result<JoinHandle<()>> {
+    listener.set_nonblocking(true)?;
+    Ok(task::Builder::new().name("prometheus".to_string()).spawn(move || loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                task::Builder::new()
+                    .name("prometheus-conn".to_string())
+                    .spawn(move || {
+                        // The client went away or misbehaved, there is nobody to report to.
+                        let _ = respond(stream);
+                    });
+            },
+            Err(err) if err.kind() == ErrorKind::WouldBlock => time::sleep(POLL_INTERVAL),
+            Err(_) => time::sleep(POLL_INTERVAL),
+        }
+    }))
+}
+
+fn respond(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_nonblocking(true)?;
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        if request.len() > 8192 || Instant::now() > deadline {
+            return Err(ErrorKind::InvalidData.into());
+        }
+        match stream.read(&mut buf) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => time::sleep(POLL_INTERVAL),
+            Err(err) => return Err(err),
+        }
+    }
+
+    let response = if request.starts_with(b"GET /metrics ") {
+        let body = render();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(), body,
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    let mut response = response.as_bytes();
+    while !response.is_empty() {
+        match stream.write(response) {
+            Ok(n) => response = &response[n..],
+            Err(err) if err.kind() == ErrorKind::WouldBlock => time::sleep(POLL_INTERVAL),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind).unwrap();
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, help, "counter");
+    writeln!(out, "{} {}", name, value).unwrap();
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: usize) {
+    header(out, name, help, "gauge");
+    writeln!(out, "{} {}", name, value).unwrap();
+}
+
+fn primitive_labels(primitive: &metrics::PrimitiveMetrics) -> String {
+    format!("kind=\"{}\",name=\"{}\"", primitive.kind, escape(&primitive.name))
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::{Read, Write}, net::{TcpListener, TcpStream}, rc::Rc, thread, time::Duration};
+
+    use crate::{sync::{mpsc, Mutex, Semaphore}, task, time};
+
+    use super::*;
+
+    #[fib::test(paused_time)]
+    fn test_render() {
+        let mutex = Rc::new(Mutex::new(0).named("db"));
+        let semaphore = Semaphore::new(1).named("pool");
+        let (tx, rx) = mpsc::channel();
+        let rx = rx.named("jobs \"queue\"");
+        tx.send(1).unwrap();
+        tx.send(2).unwrap();
+
+        let guard = mutex.lock();
+        let waiter_mutex = mutex.clone();
+        let waiter = task::spawn(move || *waiter_mutex.lock() += 1);
+        task::yield_now();
+        time::advance(Duration::from_millis(5));
+        drop(guard);
+        waiter.join();
+        drop(semaphore.acquire());
+        assert_eq!(rx.recv().unwrap(), 1);
+
+        let text = render();
+        for line in [
+            "fib_tasks_spawned_total 2",
+            "fib_blocks_total{cause=\"lock\"} 1",
+            "fib_tasks{name=\"\"} 1",
+            "fib_task_polls{name=\"\"} 2",
+            "fib_primitive_contentions_total{kind=\"mutex\",name=\"db\"} 1",
+            "fib_primitive_contentions_total{kind=\"semaphore\",name=\"pool\"} 0",
+            "fib_primitive_wait_seconds_bucket{kind=\"mutex\",name=\"db\",le=\"0.001\"} 0",
+            "fib_primitive_wait_seconds_bucket{kind=\"mutex\",name=\"db\",le=\"0.01\"} 1",
+            "fib_primitive_wait_seconds_bucket{kind=\"mutex\",name=\"db\",le=\"+Inf\"} 1",
+            "fib_primitive_wait_seconds_sum{kind=\"mutex\",name=\"db\"} 0.005",
+            "fib_primitive_queue_length{kind=\"channel\",name=\"jobs \\\"queue\\\"\"} 1",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing `{}` in\n{}", line, text);
+        }
+        assert!(!text.contains("id=\""), "{}", text);
+    }
+
+    #[fib::test]
+    fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = serve(listener).unwrap();
+
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+        while !client.is_finished() {
+            time::sleep(Duration::from_millis(5));
+        }
+        let response = client.join().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("\r\n\r\n# HELP fib_tasks_spawned_total"));
+        server.cancel();
+    }
+}
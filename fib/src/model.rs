@@ -0,0 +1,320 @@
+//! Exhaustive exploration of the interleavings of a fib program, in the spirit of loom.
+//! Fibers only ever switch at yield points, so a program has a finite number of schedules,
+//! one for each sequence of scheduling decisions. `check` runs the program once per
+//! schedule, replaying the decisions made so far and systematically trying the alternatives.
+//!
+//! Schedules which only differ in the order of independent steps are pruned,
+//! as in dynamic partial order reduction. Two steps are independent unless they touch
+//! the same fib primitive (or await the same task). `task::yield_now` is considered
+//! to depend on everything, as the yielding task most likely shares state some other way.
+//! State shared without fib primitives, e.g. through an `Rc<RefCell<_>>`, is only ordered
+//! by those yields, so disable `pruning` if such state is accessed without yielding around it.
+
+use std::{any::Any, collections::{BTreeSet, HashSet}, fmt::Display, panic::{self, AssertUnwindSafe}, rc::Rc};
+
+use crate::runtime::Runtime;
+
+/// Explore every interleaving of `f` with the default bounds. Panics with the failing schedule
+/// if `f` panics or deadlocks under any of them.
+pub fn check<F>(f: F)
+where
+    F: Fn() + 'static,
+{
+    Builder::new().check(f);
+}
+
+/// Run `f` once, following the given schedule as reported by a failing `check`.
+pub fn replay<F>(schedule: &[usize], f: F)
+where
+    F: Fn() + 'static,
+{
+    let builder = Builder::new();
+    if let Err(failure) = builder.execute(&Rc::new(f), schedule.to_vec()) {
+        panic::resume_unwind(failure.payload);
+    }
+}
+
+/// Configures the exploration.
+#[derive(Debug, Clone)]
+pub struct Builder {
+    max_executions: usize,
+    max_steps: usize,
+    pruning: bool,
+}
+
+/// Outcome of a successful exploration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub executions: usize,
+    /// Whether every schedule has been explored, i.e. `max_executions` was not hit.
+    pub complete: bool,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self {
+            max_executions: 100_000,
+            max_steps: 10_000,
+            pruning: true,
+        }
+    }
+
+    /// Stop after this many executions, leaving the remaining schedules unexplored.
+    pub fn max_executions(mut self, n: usize) -> Self {
+        self.max_executions = n;
+        self
+    }
+
+    /// Fail executions which take more than this many scheduling steps, e.g. livelocks.
+    pub fn max_steps(mut self, n: usize) -> Self {
+        self.max_steps = n;
+        self
+    }
+
+    /// Prune schedules which only reorder independent steps, on by default.
+    pub fn pruning(mut self, enabled: bool) -> Self {
+        self.pruning = enabled;
+        self
+    }
+
+    pub fn check<F>(&self, f: F) -> Stats
+    where
+        F: Fn() + 'static,
+    {
+        let f = Rc::new(f);
+        let mut nodes: Vec<Node> = vec![];
+        let mut executions = 0;
+        loop {
+            executions += 1;
+            let prefix = nodes.iter().map(|node| node.chosen).collect();
+            let steps = match self.execute(&f, prefix) {
+                Ok(steps) => steps,
+                Err(failure) => panic!(
+                    "model check failed in execution {}: {}\n  schedule: {:?} (rerun it with `fib::model::replay`)",
+                    executions, failure.message, failure.schedule,
+                ),
+            };
+
+            nodes.truncate(steps.len());
+            for step in &steps[nodes.len()..] {
+                nodes.push(Node {
+                    enabled: step.enabled.clone(),
+                    chosen: step.task,
+                    backtrack: BTreeSet::from([step.task]),
+                    done: BTreeSet::from([step.task]),
+                });
+            }
+            self.add_backtracks(&steps, &mut nodes);
+
+            // Backtrack to the deepest decision with an alternative left.
+            loop {
+                let Some(node) = nodes.last_mut() else {
+                    return Stats { executions, complete: true };
+                };
+                if let Some(&task) = node.backtrack.difference(&node.done).next() {
+                    node.done.insert(task);
+                    node.chosen = task;
+                    break;
+                }
+                nodes.pop();
+            }
+            if executions >= self.max_executions {
+                return Stats { executions, complete: false };
+            }
+        }
+    }
+
+    /// For every step, find the last earlier step of another task it depends on,
+    /// and make sure the two are tried the other way around.
+    fn add_backtracks(&self, steps: &[Step], nodes: &mut [Node]) {
+        if !self.pruning {
+            for node in nodes {
+                node.backtrack.extend(&node.enabled);
+            }
+            return;
+        }
+        for (i, step) in steps.iter().enumerate() {
+            let prev = (0..i).rev()
+                .find(|&j| steps[j].task != step.task && steps[j].access.depends_on(&step.access));
+            if let Some(j) = prev {
+                if nodes[j].enabled.contains(&step.task) {
+                    nodes[j].backtrack.insert(step.task);
+                } else {
+                    nodes[j].backtrack.extend(&nodes[j].enabled);
+                }
+            }
+        }
+    }
+
+    fn execute<F>(&self, f: &Rc<F>, prefix: Vec<usize>) -> Result<Vec<Step>, Failure>
+    where
+        F: Fn() + 'static,
+    {
+        // Suspended tasks refer to the runtime, so it must be dropped in place.
+        let (result, steps) = {
+            let mut rt = Runtime::new();
+            rt.model = Some(Execution { prefix, steps: vec![], max_steps: self.max_steps });
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                let f = f.clone();
+                rt.block_on(move || f());
+            }));
+            // Leftover tasks are torn down in FIFO order.
+            (result, rt.model.take().unwrap().steps)
+        };
+        result.map(|()| steps.clone()).map_err(|payload| Failure {
+            message: panic_message(&*payload),
+            schedule: steps.iter().map(|step| step.task).collect(),
+            payload,
+        })
+    }
+}
+
+struct Failure {
+    message: String,
+    schedule: Vec<usize>,
+    payload: Box<dyn Any + Send>,
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "<non-string panic payload>".to_string()
+    }
+}
+
+/// A scheduling decision of the exploration.
+struct Node {
+    enabled: Vec<usize>,
+    chosen: usize,
+    /// Tasks which must be tried at this point.
+    backtrack: BTreeSet<usize>,
+    /// Tasks which have been tried at this point.
+    done: BTreeSet<usize>,
+}
+
+/// A single run of the program under exploration, driven by the runtime.
+pub(crate) struct Execution {
+    prefix: Vec<usize>,
+    steps: Vec<Step>,
+    max_steps: usize,
+}
+
+/// A task running from one yield point to the next.
+#[derive(Clone)]
+struct Step {
+    task: usize,
+    enabled: Vec<usize>,
+    access: Access,
+}
+
+/// What a step touched.
+#[derive(Clone, Default)]
+struct Access {
+    all: bool,
+    primitives: HashSet<usize>,
+}
+
+impl Access {
+    fn depends_on(&self, other: &Access) -> bool {
+        self.all || other.all || !self.primitives.is_disjoint(&other.primitives)
+    }
+}
+
+impl Execution {
+    /// Pick the next task among the runnable ones, returning its index in `enabled`.
+    pub(crate) fn choose(&mut self, enabled: Vec<usize>) -> usize {
+        let n = self.steps.len();
+        if n >= self.max_steps {
+            panic!("execution exceeded {} steps", self.max_steps);
+        }
+        let task = match self.prefix.get(n) {
+            Some(&task) => {
+                assert!(enabled.contains(&task), "nondeterministic execution: task {} is not runnable at step {}", task, n);
+                task
+            },
+            None => enabled[0],
+        };
+        let i = enabled.iter().position(|&id| id == task).unwrap();
+        self.steps.push(Step { task, enabled, access: Access::default() });
+        i
+    }
+
+    /// Record that the current step touches `primitive`, or anything if `None`.
+    pub(crate) fn record(&mut self, primitive: Option<usize>) {
+        if let Some(step) = self.steps.last_mut() {
+            match primitive {
+                Some(primitive) => { step.access.primitives.insert(primitive); },
+                None => step.access.all = true,
+            }
+        }
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} executions{}", self.executions, if self.complete { "" } else { " (incomplete)" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use crate::{sync::Mutex, task};
+
+    use super::*;
+
+    #[test]
+    fn test_mutex_counter() {
+        let stats = Builder::new().check(|| {
+            let counter = Rc::new(Mutex::new(0));
+            let handles: Vec<_> = (0..3).map(|_| {
+                let counter = counter.clone();
+                task::spawn(move || {
+                    let mut guard = counter.lock();
+                    let value = *guard;
+                    task::yield_now();
+                    *guard = value + 1;
+                })
+            }).collect();
+            for handle in handles {
+                handle.join();
+            }
+            assert_eq!(*counter.lock(), 3);
+        });
+        assert!(stats.complete);
+        assert!(stats.executions > 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "model check failed in execution")]
+    fn test_lost_update() {
+        check(|| {
+            let value = Rc::new(Cell::new(0));
+            let first = task::spawn({
+                let value = value.clone();
+                move || {
+                    task::yield_now();
+                    value.set(1);
+                }
+            });
+            let second = task::spawn({
+                let value = value.clone();
+                move || value.set(2)
+            });
+            first.join();
+            second.join();
+            // Only holds if `second` runs before `first` resumes.
+            assert_eq!(value.get(), 1);
+        });
+    }
+}
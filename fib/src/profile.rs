@@ -0,0 +1,271 @@
+//! Sampling CPU profiler which attributes samples to fibers, behind the `profile` feature.
+//! The calling thread's CPU clock drives a `SIGPROF` timer. The handler walks the stack of
+//! whatever was running, which is the stack of the current fiber, and tags the sample with it.
+//! Samples are symbolized once profiling stops, into folded stacks for flamegraphs.
+
+use std::{cell::{Cell, RefCell}, collections::HashMap, ffi::c_void, io, ptr, sync::{atomic::{AtomicPtr, AtomicUsize, Ordering}, Once}, time::Duration};
+
+use crate::{task::stack, utils};
+
+/// Deepest stack recorded per sample, deeper frames are cut off.
+const MAX_DEPTH: usize = 64;
+/// Samples kept per profile, later ones are dropped.
+const CAPACITY: usize = 8192;
+
+static HANDLER: Once = Once::new();
+/// Buffer of the running profiler, null if none is running.
+static SAMPLES: AtomicPtr<Sample> = AtomicPtr::new(ptr::null_mut());
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The task the scheduler has switched into, read by the signal handler.
+    static CURRENT_TASK: Cell<usize> = const { Cell::new(usize::MAX) };
+    static ACTIVE: Cell<bool> = const { Cell::new(false) };
+    /// Names of the tasks seen while profiling, as they are gone once a task finishes.
+    static NAMES: RefCell<HashMap<usize, Option<String>>> = RefCell::new(HashMap::new());
+}
+
+#[derive(Clone, Copy)]
+struct Sample {
+    task: usize,
+    depth: usize,
+    frames: [usize; MAX_DEPTH],
+}
+
+/// A running profiler, see `start`. Dropping it stops profiling without a report.
+pub struct Profiler {
+    /// `None` once disarmed. A valid timer may well be a null pointer.
+    timer: Option<libc::timer_t>,
+    samples: Box<[Sample]>,
+}
+
+/// Samples aggregated by fiber and stack.
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    /// Folded stacks, root first and prefixed with the task, and how often each was sampled.
+    pub stacks: HashMap<String, usize>,
+    /// Samples which did not fit into the buffer.
+    pub dropped: usize,
+}
+
+/// Start sampling the calling thread `hz` times per second of CPU time.
+/// Only one profiler may run at a time. Samples walk the stack through libgcc's unwinder,
+/// which may deadlock if the thread is interrupted while unwinding itself,
+/// so keep the profiler away from code which panics or captures backtraces.
+pub fn start(hz: u32) -> Profiler {
+    assert!(hz > 0, "the sampling frequency must be positive");
+    install_handler();
+    let mut samples = vec![Sample { task: 0, depth: 0, frames: [0; MAX_DEPTH] }; CAPACITY].into_boxed_slice();
+    let prev = SAMPLES.compare_exchange(ptr::null_mut(), samples.as_mut_ptr(), Ordering::AcqRel, Ordering::Acquire);
+    assert!(prev.is_ok(), "a profiler is already running");
+    NEXT.store(0, Ordering::Relaxed);
+    ACTIVE.set(true);
+
+    let timer = unsafe {
+        let mut event: libc::sigevent = std::mem::zeroed();
+        event.sigev_notify = libc::SIGEV_THREAD_ID;
+        event.sigev_signo = libc::SIGPROF;
+        event.sigev_notify_thread_id = libc::gettid();
+        let mut timer: libc::timer_t = ptr::null_mut();
+        let res = libc::timer_create(libc::CLOCK_THREAD_CPUTIME_ID, &mut event, &mut timer);
+        assert_eq!(res, 0, "timer_create failed: {}", io::Error::last_os_error());
+
+        // A zero interval would disarm the timer.
+        let interval = (Duration::from_secs(1) / hz).max(Duration::from_nanos(1));
+        let interval = libc::timespec {
+            tv_sec: interval.as_secs() as libc::time_t,
+            tv_nsec: interval.subsec_nanos() as libc::c_long,
+        };
+        let spec = libc::itimerspec { it_interval: interval, it_value: interval };
+        let res = libc::timer_settime(timer, 0, &spec, ptr::null_mut());
+        assert_eq!(res, 0, "timer_settime failed: {}", io::Error::last_os_error());
+        timer
+    };
+    Profiler { timer: Some(timer), samples }
+}
+
+impl Profiler {
+    /// Stop sampling and symbolize what has been sampled.
+    pub fn stop(mut self) -> Profile {
+        self.disarm();
+        let taken = NEXT.load(Ordering::Relaxed);
+        let samples = &self.samples[..taken.min(CAPACITY)];
+        let labels: Vec<String> = samples.iter().map(|sample| task_label(sample.task)).collect();
+        NAMES.with_borrow_mut(HashMap::clear);
+        // Symbolizing needs far more stack than a fiber has.
+        let stacks = std::thread::scope(|s| s.spawn(|| {
+            let mut symbols = HashMap::new();
+            let mut stacks = HashMap::new();
+            for (sample, mut folded) in samples.iter().zip(labels) {
+                let frames = utils::symbolize(&sample.frames[..sample.depth], "handle_sigprof", &mut symbols);
+                for frame in frames.iter().rev() {
+                    folded.push(';');
+                    folded.push_str(frame);
+                }
+                *stacks.entry(folded).or_insert(0) += 1;
+            }
+            stacks
+        }).join().unwrap());
+        Profile { stacks, dropped: taken.saturating_sub(CAPACITY) }
+    }
+
+    fn disarm(&mut self) {
+        let Some(timer) = self.timer.take() else {
+            return;
+        };
+        unsafe {
+            libc::timer_delete(timer);
+        }
+        // The signal is only ever delivered to this thread, so no handler can be running now.
+        SAMPLES.store(ptr::null_mut(), Ordering::Release);
+        ACTIVE.set(false);
+    }
+}
+
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        self.disarm();
+        NAMES.with_borrow_mut(HashMap::clear);
+    }
+}
+
+impl Profile {
+    /// One `stack count` line per distinct stack, as consumed by `flamegraph.pl` and `inferno`.
+    pub fn folded(&self) -> String {
+        let mut lines: Vec<String> = self.stacks.iter()
+            .map(|(stack, count)| format!("{} {}", stack, count))
+            .collect();
+        lines.sort();
+        lines.join("\n")
+    }
+
+    pub fn write_folded(&self, mut out: impl io::Write) -> io::Result<()> {
+        writeln!(out, "{}", self.folded())
+    }
+}
+
+/// Called by the scheduler whenever it switches into a task, or back to itself with `usize::MAX`.
+pub(crate) fn switch_to(task: usize, name: impl FnOnce() -> Option<String>) {
+    CURRENT_TASK.set(task);
+    if ACTIVE.get() && task != usize::MAX {
+        NAMES.with_borrow_mut(|names| {
+            names.entry(task).or_insert_with(name);
+        });
+    }
+}
+
+fn task_label(task: usize) -> String {
+    if task == usize::MAX {
+        return "scheduler".to_string();
+    }
+    let name = NAMES.with_borrow(|names| names.get(&task).cloned().flatten());
+    utils::task_label(task, name.as_deref()).to_string()
+}
+
+fn install_handler() {
+    stack::install_alt_stack();
+    HANDLER.call_once(|| unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle_sigprof as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGPROF, &action, ptr::null_mut());
+    });
+}
+
+extern "C" fn handle_sigprof(_signum: libc::c_int, _info: *mut libc::siginfo_t, _ucontext: *mut c_void) {
+    let samples = SAMPLES.load(Ordering::Acquire);
+    if samples.is_null() {
+        return;
+    }
+    let i = NEXT.fetch_add(1, Ordering::Relaxed);
+    if i >= CAPACITY {
+        return;
+    }
+    // Nothing in here allocates. The walk itself goes through libgcc's unwinder though,
+    // which looks frames up under the lock of `dl_iterate_phdr` (and, with older libgcc,
+    // a mutex of its own), so a sample landing while this very thread is unwinding,
+    // e.g. a panic or a `Backtrace` capture, may deadlock.
+    let sample = unsafe { &mut *samples.add(i) };
+    sample.task = CURRENT_TASK.get();
+    let mut depth = 0;
+    unsafe {
+        backtrace::trace_unsynchronized(|frame| {
+            // The outermost frame of a fiber returns to nowhere.
+            if frame.ip().is_null() {
+                return false;
+            }
+            sample.frames[depth] = frame.ip() as usize;
+            depth += 1;
+            depth < MAX_DEPTH
+        });
+    }
+    sample.depth = depth;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{hint::black_box, time::Duration};
+
+    use crate::{runtime::metrics::thread_cpu_time, task};
+
+    /// Burn `duration` of CPU time, however long it takes on a busy machine.
+    #[inline(never)]
+    fn spin(duration: Duration) -> u64 {
+        let start = thread_cpu_time();
+        let mut x = 0u64;
+        while thread_cpu_time() - start < duration {
+            x = black_box(x.wrapping_mul(31).wrapping_add(7));
+        }
+        x
+    }
+
+    #[fib::test]
+    fn test_profile() {
+        // A second or more between samples still makes a valid timer.
+        assert!(super::start(1).stop().stacks.is_empty());
+
+        let profiler = super::start(1000);
+        task::Builder::new()
+            .name("hot".to_string())
+            .spawn(|| spin(Duration::from_millis(200)))
+            .join();
+        let profile = profiler.stop();
+
+        let folded = profile.folded();
+        let hot: usize = profile.stacks.iter()
+            .filter(|(stack, _)| stack.starts_with("task 1/hot;") && stack.contains("spin"))
+            .map(|(_, count)| count)
+            .sum();
+        // CPU timers only fire on scheduler ticks which find the thread running,
+        // so the number of samples depends on the load of the machine.
+        let total: usize = profile.stacks.values().sum();
+        assert!(hot > 0 && hot * 2 > total, "{}", folded);
+        assert!(!folded.contains("handle_sigprof"), "{}", folded);
+    }
+
+    /// Recurse until less than `headroom` bytes of the fiber stack starting at `top` are left,
+    /// then spin there.
+    #[inline(never)]
+    fn spin_deep(top: usize, headroom: usize) -> u64 {
+        let frame = black_box([1u8; 256]);
+        let sp = &frame as *const _ as usize;
+        if top - sp + headroom < crate::config::STACK_SIZE {
+            spin_deep(top, headroom) + frame[0] as u64
+        } else {
+            spin(Duration::from_millis(50))
+        }
+    }
+
+    #[fib::test]
+    fn test_profile_deep_stack() {
+        // Samples are taken on the alternate signal stack, not on what is left of the fiber's.
+        let profiler = super::start(1000);
+        task::spawn(|| {
+            let top = black_box(0u8);
+            spin_deep(&top as *const _ as usize, 2048)
+        }).join();
+        let profile = profiler.stop();
+        assert!(profile.stacks.keys().any(|stack| stack.contains("spin_deep")), "{}", profile.folded());
+    }
+}
@@ -0,0 +1,153 @@
+use std::time::Duration;
+
+use crate::{config::STACK_SIZE, runtime::{runtime::Runtime, sim::Simulation}};
+
+/// Runtime factory, which can be used in order to configure a new runtime.
+/// The thread-local runtime returned by `runtime()` is always the default one.
+#[derive(Debug)]
+pub struct Builder {
+    seed: Option<u64>,
+    simulate: bool,
+    inject_yields: bool,
+    track_stack_usage: bool,
+    track_cpu_time: bool,
+    capture_backtraces: bool,
+    record_timeline: bool,
+    stack_size: usize,
+    paused_time: bool,
+    timeout: Option<Duration>,
+    #[cfg(feature = "watchdog")]
+    watchdog: Option<Duration>,
+    max_tasks: Option<usize>,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self {
+            seed: None,
+            simulate: false,
+            inject_yields: false,
+            track_stack_usage: false,
+            track_cpu_time: false,
+            capture_backtraces: false,
+            record_timeline: false,
+            stack_size: STACK_SIZE,
+            paused_time: false,
+            timeout: None,
+            #[cfg(feature = "watchdog")]
+            watchdog: None,
+            max_tasks: None,
+        }
+    }
+
+    /// Stack size of every task, in bytes. 32KB by default.
+    pub fn stack_size(mut self, stack_size: usize) -> Self {
+        self.stack_size = stack_size;
+        self
+    }
+
+    /// Make spawning panic once `max_tasks` tasks are alive, including the root task.
+    pub fn max_tasks(mut self, max_tasks: usize) -> Self {
+        self.max_tasks = Some(max_tasks);
+        self
+    }
+
+    /// Start with the clock paused, see `time::pause`.
+    pub fn paused_time(mut self, paused: bool) -> Self {
+        self.paused_time = paused;
+        self
+    }
+
+    /// Make `block_on` panic with a dump of the tasks once it has run
+    /// for `timeout` in real time. A task which never yields cannot be interrupted though.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Schedule tasks in a pseudo-random order, which only depends on the seed.
+    /// Without an explicit seed, the seed is taken from the `FIB_SEED`
+    /// environment variable, or chosen at random.
+    /// The seed is printed whenever `block_on` panics, so that the run can be replayed.
+    pub fn simulate(mut self) -> Self {
+        self.simulate = true;
+        self
+    }
+
+    /// Simulate with the given seed, see `simulate`.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.simulate = true;
+        self.seed = Some(seed);
+        self
+    }
+
+    /// In simulation mode, randomly yield right before every sync call which may block,
+    /// e.g. `Mutex::lock` or `Receiver::recv`, to explore more interleavings.
+    pub fn inject_yields(mut self, enabled: bool) -> Self {
+        self.inject_yields = enabled;
+        self
+    }
+
+    /// See `Runtime::track_stack_usage`.
+    pub fn track_stack_usage(mut self, enabled: bool) -> Self {
+        self.track_stack_usage = enabled;
+        self
+    }
+
+    /// See `Runtime::track_cpu_time`.
+    pub fn track_cpu_time(mut self, enabled: bool) -> Self {
+        self.track_cpu_time = enabled;
+        self
+    }
+
+    /// See `Runtime::capture_backtraces`.
+    pub fn capture_backtraces(mut self, enabled: bool) -> Self {
+        self.capture_backtraces = enabled;
+        self
+    }
+
+    /// Watch for tasks which run longer than `threshold` without yielding,
+    /// see `Runtime::start_watchdog`.
+    #[cfg(feature = "watchdog")]
+    pub fn watchdog(mut self, threshold: Duration) -> Self {
+        self.watchdog = Some(threshold);
+        self
+    }
+
+    /// Record a timeline from the start, see `Runtime::start_timeline`.
+    pub fn record_timeline(mut self, enabled: bool) -> Self {
+        self.record_timeline = enabled;
+        self
+    }
+
+    /// The runtime is boxed, as suspended tasks refer to it and it must never move.
+    pub fn build(self) -> Box<Runtime> {
+        let mut rt = Box::new(Runtime::new());
+        rt.track_stack_usage(self.track_stack_usage);
+        rt.track_cpu_time(self.track_cpu_time);
+        rt.capture_backtraces(self.capture_backtraces);
+        if self.record_timeline {
+            rt.start_timeline();
+        }
+        #[cfg(feature = "watchdog")]
+        if let Some(threshold) = self.watchdog {
+            rt.start_watchdog(threshold);
+        }
+        rt.stack_size = self.stack_size;
+        rt.timeout = self.timeout;
+        rt.max_tasks = self.max_tasks;
+        if self.paused_time {
+            rt.timers.pause();
+        }
+        if self.simulate {
+            rt.sim = Some(Simulation::new(self.seed, self.inject_yields));
+        }
+        rt
+    }
+}
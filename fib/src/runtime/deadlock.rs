@@ -0,0 +1,33 @@
+//! Reporting of runtime-wide deadlocks.
+
+use std::fmt::Display;
+
+use crate::{runtime::TaskDump, task::{BlockCause, TaskId}};
+
+pub(crate) type DeadlockHook = Box<dyn FnMut(&DeadlockReport)>;
+
+/// Snapshot of a runtime in which every task is blocked and nothing can ever wake them up.
+#[derive(Debug, Clone)]
+pub struct DeadlockReport {
+    pub tasks: Vec<BlockedTask>,
+    /// Rendered by `Display`, as it also knows where each task suspended.
+    pub(crate) dump: TaskDump,
+}
+
+#[derive(Debug, Clone)]
+pub struct BlockedTask {
+    pub id: TaskId,
+    pub name: Option<String>,
+    pub cause: BlockCause,
+    /// Address of the primitive the task waits on, the id of the awaited task
+    /// for `BlockCause::Join`, the number of branches for `BlockCause::Select`,
+    /// or a key with the top bit set for `BlockCause::Timer`.
+    pub primitive: usize,
+}
+
+impl Display for DeadlockReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "deadlock detected: all {} tasks are blocked", self.tasks.len())?;
+        self.dump.fmt_tasks(f)
+    }
+}
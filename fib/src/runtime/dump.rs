@@ -0,0 +1,122 @@
+//! On-demand dumps of what every task is doing, see `Runtime::dump`.
+
+use std::{fmt::{self, Display, Write}, panic::Location};
+
+use crate::{task::{BlockCause, TaskId, TaskState}, utils::task_label};
+
+/// Snapshot of the live tasks of a runtime.
+#[derive(Debug, Clone)]
+pub struct TaskDump {
+    pub tasks: Vec<DumpedTask>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DumpedTask {
+    pub id: TaskId,
+    pub name: Option<String>,
+    pub state: TaskState,
+    /// What the task waits on, if it is blocked.
+    pub cause: Option<BlockCause>,
+    /// What the task waits on, see `BlockedTask::primitive`.
+    pub primitive: Option<usize>,
+    /// Where the task last suspended, unless it is running or has not started yet.
+    pub location: Option<&'static Location<'static>>,
+    /// Symbolized backtrace of the last suspension,
+    /// if the runtime captures them (see `Runtime::capture_backtraces`).
+    pub backtrace: Option<String>,
+}
+
+impl Display for TaskDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} tasks", self.tasks.len())?;
+        self.fmt_tasks(f)
+    }
+}
+
+impl Display for DumpedTask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ", task_label(self.id.0, self.name.as_deref()))?;
+        match (self.cause, self.primitive) {
+            (Some(BlockCause::Join), Some(primitive)) => write!(f, "blocked on Join task {}", primitive)?,
+            (Some(BlockCause::Select), Some(primitive)) => write!(f, "blocked on Select {} branches", primitive)?,
+            (Some(BlockCause::Timer), Some(_)) => write!(f, "blocked on Timer")?,
+            (Some(cause), Some(primitive)) => write!(f, "blocked on {:?} {:#x}", cause, primitive)?,
+            _ => write!(f, "{}", self.state_name())?,
+        }
+        if let Some(location) = self.location {
+            write!(f, " at {}", location)?;
+        }
+        if let Some(backtrace) = &self.backtrace {
+            for line in backtrace.lines() {
+                write!(f, "\n      {}", line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DumpedTask {
+    fn state_name(&self) -> &'static str {
+        match self.state {
+            TaskState::Ready => "runnable",
+            TaskState::Running => "running",
+            TaskState::Blocked(_) => "blocked",
+            TaskState::Finished => "finished",
+        }
+    }
+}
+
+impl TaskDump {
+    /// One indented line per task, each starting on a new line.
+    pub(crate) fn fmt_tasks(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for task in &self.tasks {
+            write!(f, "\n  {}", task)?;
+        }
+        Ok(())
+    }
+
+    /// Render the dump as a JSON object `{"tasks": [...]}`.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\"tasks\":[");
+        for (i, task) in self.tasks.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            write!(json, "{{\"id\":{},\"name\":", task.id).unwrap();
+            json_str(&mut json, task.name.as_deref());
+            write!(json, ",\"state\":\"{}\",\"cause\":", task.state_name()).unwrap();
+            json_str(&mut json, task.cause.map(|cause| format!("{:?}", cause)).as_deref());
+            json.push_str(",\"primitive\":");
+            match task.primitive {
+                Some(primitive) => write!(json, "{}", primitive).unwrap(),
+                None => json.push_str("null"),
+            }
+            json.push_str(",\"location\":");
+            json_str(&mut json, task.location.map(ToString::to_string).as_deref());
+            json.push_str(",\"backtrace\":");
+            json_str(&mut json, task.backtrace.as_deref());
+            json.push('}');
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+pub(crate) fn json_str(json: &mut String, value: Option<&str>) {
+    let Some(value) = value else {
+        json.push_str("null");
+        return;
+    };
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
@@ -0,0 +1,80 @@
+//! Scheduler counters, see `Runtime::metrics`.
+
+use std::time::Duration;
+
+use crate::task::{BlockCause, TaskId};
+
+/// Snapshot of the counters of a runtime. Counts are cumulative since the runtime was built.
+#[derive(Debug, Clone, Default)]
+pub struct RuntimeMetrics {
+    pub spawned: u64,
+    /// Tasks which have finished, whether they returned, panicked or were cancelled.
+    pub completed: u64,
+    /// Times a task yielded and stayed runnable.
+    pub yields: u64,
+    /// Times the scheduler switched into a task.
+    pub context_switches: u64,
+    /// Times a blocked task was made runnable again.
+    pub wakeups: u64,
+    /// Times a task ran for longer than the watchdog threshold without yielding,
+    /// see `Runtime::start_watchdog`. Always 0 without the `watchdog` feature.
+    pub stalls: u64,
+    pub(crate) blocks: [u64; BlockCause::ALL.len()],
+    pub running_tasks: usize,
+    pub peak_running_tasks: usize,
+    pub blocking_tasks: usize,
+    pub peak_blocking_tasks: usize,
+    /// The live tasks, ordered by id.
+    pub tasks: Vec<TaskMetrics>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TaskMetrics {
+    pub id: TaskId,
+    pub name: Option<String>,
+    /// Times the task was switched into.
+    pub polls: u64,
+    /// Real time spent running the task.
+    pub busy: Duration,
+    /// Thread CPU time spent running the task, zero unless `Runtime::track_cpu_time` is on.
+    pub cpu_time: Duration,
+}
+
+/// Thread CPU time spent by the tasks of one name, see `Runtime::cpu_report`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuUsage {
+    pub name: Option<String>,
+    /// Tasks of this name, finished or alive.
+    pub tasks: u64,
+    pub cpu_time: Duration,
+}
+
+impl RuntimeMetrics {
+    /// Times a task blocked for the given cause.
+    pub fn blocks(&self, cause: BlockCause) -> u64 {
+        self.blocks[cause as usize]
+    }
+
+    /// Times a task blocked, for any cause.
+    pub fn total_blocks(&self) -> u64 {
+        self.blocks.iter().sum()
+    }
+
+    pub(crate) fn record_block(&mut self, cause: BlockCause) {
+        self.blocks[cause as usize] += 1;
+    }
+
+    pub(crate) fn record_queues(&mut self, running: usize, blocking: usize) {
+        self.peak_running_tasks = self.peak_running_tasks.max(running);
+        self.peak_blocking_tasks = self.peak_blocking_tasks.max(blocking);
+    }
+}
+
+/// CPU time consumed by the calling thread so far.
+pub(crate) fn thread_cpu_time() -> Duration {
+    let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut now);
+    }
+    Duration::new(now.tv_sec as u64, now.tv_nsec as u32)
+}
@@ -1,27 +1,76 @@
 // Runtime is the core of the Fib system, handling task management and execution.
 
+#[allow(clippy::module_inception)]
 pub(crate) mod runtime;
+pub(crate) mod deadlock;
+pub(crate) mod builder;
+pub(crate) mod sim;
+pub(crate) mod dump;
+pub(crate) mod metrics;
+pub(crate) mod trace;
+pub(crate) mod timeline;
+#[cfg(feature = "watchdog")]
+pub(crate) mod watchdog;
+
+use std::{cell::Cell, panic::{self, AssertUnwindSafe}, ptr};
 
 use context::Transfer;
 
-use crate::{runtime::runtime::Runtime, task::{packet::Packet, BlockCause}, utils::STCell};
+use crate::{task::{packet::Packet, BlockCause, Cancelled, TaskId}, utils::STCell};
+
+pub use runtime::{Runtime, ShutdownMode};
+pub use builder::Builder;
+pub use deadlock::{BlockedTask, DeadlockReport};
+pub use dump::{DumpedTask, TaskDump};
+pub use metrics::{CpuUsage, RuntimeMetrics, TaskMetrics};
+pub use timeline::{RunInterval, StopReason, Timeline};
+pub use crate::task::stack::{StackReport, StackStats};
 
 thread_local! {
     pub(crate) static RUNTIME: STCell<Runtime> = STCell::new(Runtime::new());
+    /// The runtime currently driving fibers on this thread, if any.
+    /// Unlike `RUNTIME` it has no destructor, so it stays accessible while `RUNTIME` itself is dropped.
+    static CURRENT: Cell<*mut Runtime> = const { Cell::new(ptr::null_mut()) };
 }
 
 pub fn runtime() -> &'static mut Runtime {
+    let current = CURRENT.get();
+    if !current.is_null() {
+        return unsafe { &mut *current };
+    }
     RUNTIME.with(|cell| unsafe {
         (*cell.inner.get()).as_mut().unwrap()
     })
 }
 
-pub fn wake_task(id: usize) {
-    runtime().wake_task(id);
+/// Makes `runtime()` return the given runtime until dropped.
+pub(crate) struct EnterGuard {
+    prev: *mut Runtime,
 }
 
-pub fn cur_task() -> usize {
-    runtime().cur_task()
+pub(crate) fn enter(rt: &mut Runtime) -> EnterGuard {
+    EnterGuard { prev: CURRENT.replace(rt) }
+}
+
+impl Drop for EnterGuard {
+    fn drop(&mut self) {
+        CURRENT.set(self.prev);
+    }
+}
+
+/// Make a blocked task runnable again. Tasks which are not blocked are left alone.
+pub fn wake_task(id: TaskId) {
+    runtime().wake_task(id.as_usize());
+}
+
+/// See `Runtime::wake_waiter`.
+pub(crate) fn wake_waiter(id: usize, primitive: usize) -> bool {
+    runtime().wake_waiter(id, primitive)
+}
+
+/// The id of the task which is currently running.
+pub fn cur_task() -> TaskId {
+    TaskId(runtime().cur_task())
 }
 
 pub(crate) extern "C" fn task_entry<R: 'static>(to_base: Transfer) -> ! {
@@ -35,9 +84,19 @@ pub(crate) extern "C" fn task_entry<R: 'static>(to_base: Transfer) -> ! {
 
     rt.set_base_cx(unsafe { to_base.context.resume(42).context });
 
-    let result = closure();
+    // A task cancelled before its first run never starts.
+    let result = if rt.take_cancelled() {
+        drop(closure);
+        Err(Box::new(Cancelled) as _)
+    } else {
+        panic::catch_unwind(AssertUnwindSafe(closure))
+    };
 
-    let result = Packet::result(result);
+    let result = match result {
+        Ok(result) => Packet::result(result),
+        Err(payload) if payload.is::<Cancelled>() => Packet::cancelled(),
+        Err(payload) => Packet::panic(payload),
+    };
 
     let base_cx = rt.get_base_cx().expect("Base context not set");
     let to_base = Transfer::new(base_cx, 0);
@@ -48,16 +107,15 @@ pub(crate) extern "C" fn task_entry<R: 'static>(to_base: Transfer) -> ! {
 
 #[cfg(test)]
 mod tests {
-    use std::rc::Rc;
+    use std::{cell::Cell, rc::Rc, time::Duration};
 
-    use crate::{sync::mutex::Mutex, task};
+    use crate::{sync::{mutex::Mutex, Notify}, task, time};
 
     use super::*;
 
-    #[test]
+    #[fib::test]
     fn test_entry() {
-        let mut rt = runtime();
-        rt.block_on(test_mutex);
+        test_mutex();
     }
     
     fn test_mutex() {
@@ -83,4 +141,469 @@ mod tests {
 
         println!("Final counter value: {}", *counter.lock());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_stack_usage() {
+        let mut rt = Builder::new().track_stack_usage(true).build();
+        rt.block_on(|| {
+            let handle = task::Builder::new()
+                .name("buffer".to_string())
+                .spawn(|| {
+                    let buf = [1u8; 4096];
+                    std::hint::black_box(&buf);
+                });
+            handle.join();
+        });
+        let stats = rt.stack_report().stats();
+        let buffer = stats.iter().find(|stats| stats.name == "buffer").unwrap();
+        assert_eq!(buffer.samples, 1);
+        assert!(buffer.max >= 4096 && buffer.max < crate::config::STACK_SIZE);
+        assert!(buffer.p99 >= buffer.max);
+    }
+
+    #[test]
+    fn test_stack_usage_after_teardown() {
+        struct Stuck(Notify);
+
+        impl Drop for Stuck {
+            fn drop(&mut self) {
+                self.0.wait();
+            }
+        }
+
+        let mut rt = Builder::new().track_stack_usage(true).build();
+        let handle = rt.block_on(|| {
+            let handle = task::spawn(|| {
+                let _stuck = Stuck(Notify::new());
+                let buf = [1u8; 4096];
+                std::hint::black_box(&buf);
+                Notify::new().wait();
+            });
+            task::yield_now();
+            handle
+        });
+        // The task gets stuck while unwinding, so its stack is freed without it finishing.
+        rt.shutdown(ShutdownMode::Cancel);
+        let used = handle.stack_usage().unwrap();
+        assert!((4096..crate::config::STACK_SIZE).contains(&used));
+    }
+
+    struct DropFlag(Rc<Cell<usize>>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn test_shutdown_cancel() {
+        let mut rt = runtime();
+        let dropped = Rc::new(Cell::new(0));
+        let notify = Notify::new();
+        rt.block_on({
+            let dropped = dropped.clone();
+            move || {
+                let flag = DropFlag(dropped.clone());
+                task::spawn(move || {
+                    let _flag = flag;
+                    notify.wait();
+                    unreachable!();
+                });
+                task::yield_now();
+                // Never gets to run at all.
+                let flag = DropFlag(dropped);
+                task::spawn(move || {
+                    let _flag = flag;
+                    unreachable!();
+                });
+            }
+        });
+        assert_eq!(dropped.get(), 0);
+        rt.shutdown(ShutdownMode::Cancel);
+        assert_eq!(dropped.get(), 2);
+    }
+
+    #[test]
+    fn test_shutdown_drain() {
+        let mut rt = runtime();
+        let done = Rc::new(Cell::new(false));
+        rt.block_on({
+            let done = done.clone();
+            move || {
+                task::spawn(move || {
+                    for _ in 0..3 {
+                        task::yield_now();
+                    }
+                    done.set(true);
+                });
+            }
+        });
+        assert!(!done.get());
+        rt.shutdown(ShutdownMode::Drain(Duration::from_secs(1)));
+        assert!(done.get());
+    }
+
+    #[test]
+    fn test_shutdown_drain_sleeps() {
+        let mut rt = runtime();
+        rt.block_on(|| {
+            task::spawn(|| Notify::new().wait());
+            task::spawn(|| crate::time::sleep(Duration::from_secs(10)));
+        });
+        let start = metrics::thread_cpu_time();
+        rt.shutdown(ShutdownMode::Drain(Duration::from_millis(100)));
+        assert!(metrics::thread_cpu_time() - start < Duration::from_millis(50));
+    }
+
+    #[fib::test]
+    fn test_cancel_blocked_waiter() {
+        let mutex = Rc::new(Mutex::new(0));
+        let guard = mutex.lock();
+        let spawn_locker = |mutex: Rc<Mutex<i32>>| task::spawn(move || *mutex.lock() += 1);
+        let a = spawn_locker(mutex.clone());
+        let b = spawn_locker(mutex.clone());
+        task::yield_now();
+        a.cancel();
+        task::yield_now();
+        // a is still queued on the mutex, the unlock must be handed on to b.
+        drop(guard);
+        b.join();
+        assert_eq!(*mutex.lock(), 1);
+
+        let semaphore = Rc::new(crate::sync::Semaphore::new(1));
+        let permit = semaphore.acquire().unwrap();
+        let spawn_acquirer = |semaphore: Rc<crate::sync::Semaphore>| task::spawn(move || {
+            drop(semaphore.acquire().unwrap());
+        });
+        let c = spawn_acquirer(semaphore.clone());
+        let d = spawn_acquirer(semaphore.clone());
+        task::yield_now();
+        c.cancel();
+        task::yield_now();
+        drop(permit);
+        d.join();
+        assert_eq!(semaphore.available_permits(), 1);
+    }
+
+    #[fib::test]
+    fn test_cancel_woken_waiter() {
+        // a is handed the primitive, but cancelled before it runs, so it must pass it on to b.
+        let mutex = Rc::new(Mutex::new(0));
+        let guard = mutex.lock();
+        let spawn_locker = |mutex: Rc<Mutex<i32>>| task::spawn(move || *mutex.lock() += 1);
+        let a = spawn_locker(mutex.clone());
+        let b = spawn_locker(mutex.clone());
+        task::yield_now();
+        drop(guard);
+        a.cancel();
+        b.join();
+        assert_eq!(*mutex.lock(), 1);
+
+        let semaphore = Rc::new(crate::sync::Semaphore::new(1));
+        let permit = semaphore.acquire().unwrap();
+        let spawn_acquirer = |semaphore: Rc<crate::sync::Semaphore>| task::spawn(move || {
+            drop(semaphore.acquire().unwrap());
+        });
+        let a = spawn_acquirer(semaphore.clone());
+        let b = spawn_acquirer(semaphore.clone());
+        task::yield_now();
+        drop(permit);
+        a.cancel();
+        b.join();
+        assert_eq!(semaphore.available_permits(), 1);
+
+        let rwlock = Rc::new(crate::sync::RwLock::new(0));
+        let guard = rwlock.write();
+        let spawn_writer = |rwlock: Rc<crate::sync::RwLock<i32>>| task::spawn(move || *rwlock.write() += 1);
+        let a = spawn_writer(rwlock.clone());
+        let b = spawn_writer(rwlock.clone());
+        task::yield_now();
+        drop(guard);
+        a.cancel();
+        b.join();
+        assert_eq!(*rwlock.read(), 1);
+
+        let (tx, rx) = crate::sync::mpsc::sync_channel(1);
+        tx.send(0).unwrap();
+        let spawn_sender = |tx: crate::sync::mpsc::SyncSender<i32>, item| task::spawn(move || tx.send(item).unwrap());
+        let a = spawn_sender(tx.clone(), 1);
+        let b = spawn_sender(tx, 2);
+        task::yield_now();
+        assert_eq!(rx.recv().unwrap(), 0);
+        a.cancel();
+        b.join();
+        assert_eq!(rx.recv().unwrap(), 2);
+
+        let notify = Notify::new();
+        let spawn_waiter = |notify: Notify| task::spawn(move || notify.wait());
+        let a = spawn_waiter(notify.clone());
+        let b = spawn_waiter(notify.clone());
+        task::yield_now();
+        notify.notify_one();
+        a.cancel();
+        b.join();
+    }
+
+    #[fib::test]
+    fn test_cancel_then_recv() {
+        let (tx, rx) = crate::sync::mpsc::channel();
+        let rx = Rc::new(rx);
+        let receiver = task::spawn({
+            let rx = rx.clone();
+            move || rx.recv().unwrap()
+        });
+        task::yield_now();
+        receiver.cancel();
+        task::yield_now();
+        assert!(receiver.is_finished());
+        tx.send(1).unwrap();
+        assert_eq!(rx.recv().unwrap(), 1);
+    }
+
+    #[fib::test]
+    #[should_panic(expected = "deadlock detected: all 3 tasks are blocked")]
+    fn test_deadlock() {
+        let a = Rc::new(Mutex::new(()));
+        let b = Rc::new(Mutex::new(()));
+        let first = task::Builder::new().name("ab".to_string()).spawn({
+            let (a, b) = (a.clone(), b.clone());
+            move || {
+                let _a = a.lock();
+                task::yield_now();
+                let _b = b.lock();
+            }
+        });
+        let _second = task::Builder::new().name("ba".to_string()).spawn(move || {
+            let _b = b.lock();
+            task::yield_now();
+            let _a = a.lock();
+        });
+        first.join();
+    }
+
+    #[fib::test(timeout = "100ms")]
+    #[should_panic(expected = "block_on timed out after 100ms: 2 tasks\n  task 0/<unnamed> blocked on Join task 1 at ")]
+    fn test_timeout() {
+        task::Builder::new().name("spinner".to_string()).spawn(|| loop {
+            task::yield_now();
+        }).join();
+    }
+
+    #[fib::test(seed = 7, stack_size = 65536, paused_time)]
+    fn test_configured() {
+        let start = crate::time::now();
+        task::spawn(|| {
+            let buf = [1u8; 40000];
+            std::hint::black_box(&buf);
+            crate::time::sleep(Duration::from_secs(30));
+        }).join();
+        assert_eq!(crate::time::now() - start, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_dump() {
+        let mut rt = Builder::new().capture_backtraces(true).build();
+        rt.block_on(|| {
+            let notify = Rc::new(Notify::new());
+            let waiter_notify = notify.clone();
+            let waiter = task::Builder::new()
+                .name("waiter".to_string())
+                .spawn(move || waiter_notify.wait());
+            let sleeper = task::Builder::new()
+                .name("sleeper".to_string())
+                .spawn(|| time::sleep(Duration::from_millis(1)));
+            task::yield_now();
+
+            let dump = runtime().dump();
+            let text = dump.to_string();
+            assert!(text.starts_with("3 tasks\n  task 0/<unnamed> running\n  task 1/waiter blocked on Notify 0x"), "{}", text);
+            assert!(text.contains("\n  task 2/sleeper blocked on Timer at "), "{}", text);
+            assert!(text.contains(&format!(" at {}:", file!())), "{}", text);
+            assert!(dump.tasks[1].backtrace.as_ref().is_some_and(|backtrace| !backtrace.is_empty()));
+            assert!(dump.to_json().starts_with(r#"{"tasks":[{"id":0,"name":null,"state":"running","cause":null"#));
+
+            notify.notify_one();
+            waiter.join();
+            sleeper.join();
+        });
+    }
+
+    #[test]
+    fn test_metrics() {
+        let mut rt = Builder::new().build();
+        rt.block_on(|| {
+            let mutex = Rc::new(Mutex::new(0));
+            let guard = mutex.lock();
+            let worker_mutex = mutex.clone();
+            let worker = task::Builder::new()
+                .name("worker".to_string())
+                .spawn(move || *worker_mutex.lock() += 1);
+            task::yield_now();
+            drop(guard);
+
+            let metrics = runtime().metrics();
+            assert_eq!((metrics.running_tasks, metrics.blocking_tasks), (1, 0));
+            let polls: Vec<_> = metrics.tasks.iter().map(|task| (task.name.as_deref(), task.polls)).collect();
+            assert_eq!(polls, [(None, 1), (Some("worker"), 1)]);
+
+            task::yield_now();
+            worker.join();
+        });
+
+        let metrics = rt.metrics();
+        assert_eq!((metrics.spawned, metrics.completed), (2, 2));
+        assert_eq!((metrics.yields, metrics.context_switches, metrics.wakeups), (2, 5, 1));
+        assert_eq!(metrics.blocks(task::BlockCause::Lock), 1);
+        assert_eq!(metrics.total_blocks(), 1);
+        assert_eq!((metrics.peak_running_tasks, metrics.peak_blocking_tasks), (2, 1));
+        assert!(metrics.tasks.is_empty());
+    }
+
+    #[test]
+    fn test_timeline() {
+        let mut rt = Builder::new().record_timeline(true).build();
+        rt.block_on(|| {
+            let worker = task::Builder::new()
+                .name("worker".to_string())
+                .spawn(task::yield_now);
+            worker.join();
+        });
+        let timeline = rt.stop_timeline().unwrap();
+        let intervals: Vec<_> = timeline.intervals.iter()
+            .map(|interval| (interval.task.as_usize(), interval.name.as_deref(), interval.stop))
+            .collect();
+        assert_eq!(intervals, [
+            (0, None, StopReason::Block(task::BlockCause::Join)),
+            (1, Some("worker"), StopReason::Yield),
+            (1, Some("worker"), StopReason::Finish),
+            (0, None, StopReason::Finish),
+        ]);
+        assert!(timeline.intervals.iter().zip(timeline.intervals.iter().skip(1)).all(|(a, b)| a.start + a.duration <= b.start));
+        assert_eq!(timeline.dropped, 0);
+
+        let trace = timeline.to_chrome_trace();
+        assert!(trace.starts_with(r#"{"displayTimeUnit":"ns","traceEvents":[{"name":"thread_name""#));
+        assert!(trace.contains(r#"{"name":"task 1/worker","cat":"fib","ph":"X","pid":1,"tid":1,"ts":"#));
+        assert!(trace.contains(r#""args":{"task":0,"stop":"block Join"}}"#));
+        assert!(trace.ends_with("}}]}"));
+    }
+
+    #[test]
+    fn test_timeline_capacity() {
+        let mut rt = Builder::new().build();
+        rt.start_timeline_with_capacity(2);
+        rt.block_on(|| task::spawn(task::yield_now).join());
+        let timeline = rt.stop_timeline().unwrap();
+        let stops: Vec<_> = timeline.intervals.iter().map(|interval| interval.stop).collect();
+        assert_eq!(stops, [StopReason::Finish, StopReason::Finish]);
+        assert_eq!(timeline.dropped, 2);
+    }
+
+    /// Burn `duration` of CPU time, however long it takes on a busy machine.
+    fn spin(duration: Duration) {
+        let start = metrics::thread_cpu_time();
+        while metrics::thread_cpu_time() - start < duration {
+            std::hint::spin_loop();
+        }
+    }
+
+    #[test]
+    fn test_cpu_time() {
+        let mut rt = Builder::new().track_cpu_time(true).build();
+        rt.block_on(|| {
+            let workers: Vec<_> = (0..2)
+                .map(|_| task::Builder::new()
+                    .name("worker".to_string())
+                    .spawn(|| {
+                        spin(Duration::from_millis(10));
+                        task::yield_now();
+                        spin(Duration::from_millis(10));
+                    }))
+                .collect();
+            let sleeper = task::Builder::new()
+                .name("sleeper".to_string())
+                .spawn(|| crate::time::sleep(Duration::from_millis(30)));
+            let worker = runtime().tasks().find(|task| task.name() == Some("worker")).unwrap();
+
+            for handle in &workers {
+                task::wait(handle.id());
+                assert!(handle.cpu_time() >= Duration::from_millis(20));
+            }
+            task::wait(sleeper.id());
+            assert!(sleeper.cpu_time() < Duration::from_millis(10));
+            assert!(worker.cpu_time() >= Duration::from_millis(20));
+        });
+
+        let report = rt.cpu_report();
+        assert_eq!(report[0].name.as_deref(), Some("worker"));
+        assert_eq!(report[0].tasks, 2);
+        assert!(report[0].cpu_time >= Duration::from_millis(40));
+        let sleeper = report.iter().find(|usage| usage.name.as_deref() == Some("sleeper")).unwrap();
+        assert!(sleeper.cpu_time < Duration::from_millis(10));
+
+        // A task stuck while unwinding is torn down by shutdown without finishing, and still counts.
+        struct Stuck(Notify);
+
+        impl Drop for Stuck {
+            fn drop(&mut self) {
+                self.0.wait();
+            }
+        }
+
+        rt.block_on(|| {
+            task::Builder::new()
+                .name("stuck".to_string())
+                .spawn(|| {
+                    let _stuck = Stuck(Notify::new());
+                    spin(Duration::from_millis(10));
+                    Notify::new().wait();
+                });
+            task::yield_now();
+        });
+        rt.shutdown(ShutdownMode::Cancel);
+        let report = rt.cpu_report();
+        let stuck = report.iter().find(|usage| usage.name.as_deref() == Some("stuck")).unwrap();
+        assert_eq!(stuck.tasks, 1);
+        assert!(stuck.cpu_time >= Duration::from_millis(10));
+
+        let mut rt = Builder::new().build();
+        rt.block_on(|| spin(Duration::from_millis(10)));
+        assert!(rt.cpu_report().iter().all(|usage| usage.cpu_time.is_zero()));
+    }
+
+    #[cfg(feature = "watchdog")]
+    #[test]
+    fn test_watchdog() {
+        // Stints are timed on the wall clock, so leave a wide margin for a busy machine.
+        let mut rt = Builder::new().watchdog(Duration::from_millis(100)).build();
+        rt.block_on(|| {
+            task::Builder::new()
+                .name("polite".to_string())
+                .spawn(|| for _ in 0..20 {
+                    spin(Duration::from_millis(1));
+                    task::yield_now();
+                })
+                .join();
+            assert_eq!(runtime().metrics().stalls, 0);
+            task::Builder::new()
+                .name("hog".to_string())
+                .spawn(|| spin(Duration::from_millis(250)))
+                .join();
+        });
+        // Counted once per stint, however long it is.
+        assert_eq!(rt.metrics().stalls, 1);
+        rt.stop_watchdog();
+        assert_eq!(rt.metrics().stalls, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot spawn more than 2 tasks")]
+    fn test_max_tasks() {
+        let mut rt = Builder::new().max_tasks(2).build();
+        rt.block_on(|| {
+            let _first = task::spawn(task::yield_now);
+            let _second = task::spawn(task::yield_now);
+        });
+    }
+}
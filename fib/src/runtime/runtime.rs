@@ -1,8 +1,22 @@
-use std::{collections::{HashMap, VecDeque}, rc::Rc};
+use std::{backtrace::Backtrace, collections::{HashMap, HashSet, VecDeque}, panic::{self, Location}, rc::Rc, time::{Duration, Instant}};
 
 use context::{Context, Transfer};
 
-use crate::task::{packet::Packet, task::{AnyTask, JoinHandle, Task, TaskState}, BlockCause};
+use crate::{cell, config::STACK_SIZE, model::Execution, signal::{self, DumpFormat}, time::Timers};
+use crate::runtime::{deadlock::{BlockedTask, DeadlockHook, DeadlockReport}, dump::{DumpedTask, TaskDump}, enter, metrics::{CpuUsage, RuntimeMetrics, TaskMetrics}, timeline::{StopReason, Timeline}, trace, sim::{SeedReporter, Simulation}};
+#[cfg(feature = "watchdog")]
+use crate::runtime::watchdog::Watchdog;
+use crate::task::{no_yield, packet::Packet, stack::{self, StackReport}, task::{AnyTask, JoinHandle, Task, TaskInfo, TaskState}, BlockCause, Cancelled, TaskId, TaskRef};
+
+/// How `Runtime::shutdown` deals with the tasks that are still alive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownMode {
+    /// Cancel every remaining task right away.
+    Cancel,
+    /// Let the remaining tasks run until they are done or the timeout expires,
+    /// then cancel whatever is left.
+    Drain(Duration),
+}
 
 
 /// SAFETY  We have multiple mutable references to the runtime at the same time,
@@ -14,26 +28,240 @@ pub struct Runtime {
     pub(crate) cxs: HashMap<usize, Context>,
     pub(crate) running_tasks: VecDeque<Box<dyn AnyTask>>,
     pub(crate) blocking_tasks: HashMap<usize, Box<dyn AnyTask>>,
+    /// Every live task, including the one currently running.
+    pub(crate) tasks: HashMap<usize, TaskInfo>,
+    /// What each blocked task waits on, for diagnostics.
+    waiting_on: HashMap<usize, usize>,
+    pub(crate) joiners: HashMap<usize, Vec<usize>>,
     cur_task: usize,
     next_id: usize,
+    cancelled: HashSet<usize>,
+    pub(crate) metrics: RuntimeMetrics,
+    timeline: Option<Timeline>,
+    #[cfg(feature = "watchdog")]
+    pub(crate) watchdog: Option<Watchdog>,
+    pub(crate) stack_size: usize,
+    track_stack_usage: bool,
+    pub(crate) track_cpu_time: bool,
+    capture_backtraces: bool,
+    /// Real time after which `block_on` gives up.
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) max_tasks: Option<usize>,
+    pub(crate) stack_report: StackReport,
+    /// Number of tasks and CPU time of the finished tasks, by name.
+    pub(crate) cpu_by_name: HashMap<Option<String>, (u64, Duration)>,
+    deadlock_hook: Option<DeadlockHook>,
+    pub(crate) sim: Option<Simulation>,
+    pub(crate) model: Option<Execution>,
+    pub(crate) timers: Timers,
 }
 
 impl Runtime {
     pub(crate) fn new() -> Self {
+        stack::install_overflow_handler();
         Self {
             base_cx: None,
             cxs: HashMap::new(),
             running_tasks: VecDeque::new(),
             blocking_tasks: HashMap::new(),
+            tasks: HashMap::new(),
+            waiting_on: HashMap::new(),
+            joiners: HashMap::new(),
             cur_task: usize::MAX,
             next_id: 0,
+            cancelled: HashSet::new(),
+            metrics: RuntimeMetrics::default(),
+            timeline: None,
+            #[cfg(feature = "watchdog")]
+            watchdog: None,
+            stack_size: STACK_SIZE,
+            track_stack_usage: false,
+            track_cpu_time: false,
+            capture_backtraces: false,
+            timeout: None,
+            max_tasks: None,
+            stack_report: StackReport::default(),
+            cpu_by_name: HashMap::new(),
+            deadlock_hook: None,
+            sim: None,
+            model: None,
+            timers: Timers::new(),
+        }
+    }
+
+    /// Install a hook which is called with a report once every task is blocked
+    /// and none of them can ever be woken up. The runtime panics after the hook returns.
+    pub fn on_deadlock(&mut self, hook: impl FnMut(&DeadlockReport) + 'static) {
+        self.deadlock_hook = Some(Box::new(hook));
+    }
+
+    /// Paint the stacks of tasks spawned from now on, so that their high-water mark
+    /// can be measured. Painting commits the whole stack, so this is off by default.
+    pub fn track_stack_usage(&mut self, enabled: bool) {
+        self.track_stack_usage = enabled;
+    }
+
+    /// Measure the thread CPU time of every task, see `TaskRef::cpu_time` and `cpu_report`.
+    /// This reads the thread's CPU clock twice per context switch, so it is off by default.
+    pub fn track_cpu_time(&mut self, enabled: bool) {
+        self.track_cpu_time = enabled;
+    }
+
+    /// Capture a backtrace whenever a task suspends, to be shown by `dump`.
+    /// Capturing is expensive, so this is off by default.
+    pub fn capture_backtraces(&mut self, enabled: bool) {
+        self.capture_backtraces = enabled;
+    }
+
+    /// Scheduler counters, queue depths and the time each live task has spent running.
+    /// Counting is cheap, so it is always on.
+    pub fn metrics(&self) -> RuntimeMetrics {
+        let mut ids: Vec<usize> = self.tasks.keys().copied().collect();
+        ids.sort();
+        RuntimeMetrics {
+            running_tasks: self.running_tasks.len(),
+            blocking_tasks: self.blocking_tasks.len(),
+            tasks: ids.into_iter()
+                .map(|id| {
+                    let info = &self.tasks[&id];
+                    TaskMetrics {
+                        id: TaskId(id),
+                        name: info.name.clone(),
+                        polls: info.polls,
+                        busy: info.busy,
+                        cpu_time: info.cpu_time.get(),
+                    }
+                })
+                .collect(),
+            #[cfg(feature = "watchdog")]
+            stalls: self.metrics.stalls + self.watchdog.as_ref().map_or(0, Watchdog::stalls),
+            ..self.metrics.clone()
+        }
+    }
+
+    /// Thread CPU time spent by all tasks so far, finished or alive, aggregated by name
+    /// and ordered by CPU time, most first. Only counts while `track_cpu_time` is on.
+    pub fn cpu_report(&self) -> Vec<CpuUsage> {
+        let mut by_name = self.cpu_by_name.clone();
+        for info in self.tasks.values() {
+            let usage = by_name.entry(info.name.clone()).or_default();
+            usage.0 += 1;
+            usage.1 += info.cpu_time.get();
         }
+        let mut report: Vec<CpuUsage> = by_name.into_iter()
+            .map(|(name, (tasks, cpu_time))| CpuUsage { name, tasks, cpu_time })
+            .collect();
+        report.sort_by(|a, b| b.cpu_time.cmp(&a.cpu_time).then_with(|| a.name.cmp(&b.name)));
+        report
+    }
+
+    /// Fold the CPU time of a task that is gone into `cpu_report`.
+    pub(crate) fn record_cpu_time(&mut self, name: Option<String>, cpu_time: Duration) {
+        let usage = self.cpu_by_name.entry(name).or_default();
+        usage.0 += 1;
+        usage.1 += cpu_time;
+    }
+
+    /// Start a thread which reports every task that runs for longer than `threshold`
+    /// without returning to the scheduler, along with a backtrace of where it is.
+    /// Such stalls are also counted by `metrics`. Replaces any previous watchdog.
+    /// The runtime thread is interrupted with `SIGURG`, other `SIGURG`s are passed on
+    /// to the handler installed before the first watchdog started, if any.
+    #[cfg(feature = "watchdog")]
+    pub fn start_watchdog(&mut self, threshold: Duration) {
+        self.stop_watchdog();
+        let names = self.tasks.iter()
+            .filter_map(|(&id, info)| Some((id, info.name.clone()?)))
+            .collect();
+        self.watchdog = Some(Watchdog::start(threshold, names));
+    }
+
+    #[cfg(feature = "watchdog")]
+    pub fn stop_watchdog(&mut self) {
+        if let Some(watchdog) = self.watchdog.take() {
+            self.metrics.stalls += watchdog.stalls();
+        }
+    }
+
+    /// Start recording every run interval of every task, discarding any previous recording.
+    /// Only the latest `Timeline::DEFAULT_CAPACITY` intervals are kept. See `stop_timeline`.
+    pub fn start_timeline(&mut self) {
+        self.start_timeline_with_capacity(Timeline::DEFAULT_CAPACITY);
+    }
+
+    /// Like `start_timeline`, keeping the latest `capacity` intervals.
+    pub fn start_timeline_with_capacity(&mut self, capacity: usize) {
+        self.timeline = Some(Timeline::new(capacity));
+    }
+
+    /// Stop recording and return what has been recorded since `start_timeline`.
+    pub fn stop_timeline(&mut self) -> Option<Timeline> {
+        self.timeline.take()
+    }
+
+    /// What every live task is doing: its state, what it is blocked on
+    /// and where it suspended, ordered by id.
+    pub fn dump(&self) -> TaskDump {
+        let mut ids: Vec<usize> = self.tasks.keys().copied().collect();
+        ids.sort();
+        let tasks = ids.into_iter()
+            .map(|id| {
+                let info = &self.tasks[&id];
+                let state = self.task_state(id);
+                let cause = match state {
+                    TaskState::Blocked(cause) => Some(cause),
+                    _ => None,
+                };
+                let suspended = state != TaskState::Running;
+                DumpedTask {
+                    id: TaskId(id),
+                    name: info.name.clone(),
+                    state,
+                    cause,
+                    primitive: cause.and(self.waiting_on.get(&id).copied()),
+                    location: info.suspended_at.filter(|_| suspended),
+                    backtrace: info.backtrace.as_ref().filter(|_| suspended).map(ToString::to_string),
+                }
+            })
+            .collect();
+        TaskDump { tasks }
+    }
+
+    /// Stack usage of finished tasks, grouped by task name.
+    pub fn stack_report(&self) -> &StackReport {
+        &self.stack_report
+    }
+
+    /// Seed of the simulation, if the runtime was built in simulation mode.
+    pub fn seed(&self) -> Option<u64> {
+        self.sim.as_ref().map(|sim| sim.seed)
     }
 
     pub(crate) fn cur_task(&self) -> usize {
         self.cur_task
     }
 
+    /// The live tasks, ordered by id.
+    pub fn tasks(&self) -> impl Iterator<Item = TaskRef> + use<> {
+        let mut ids: Vec<usize> = self.tasks.keys().copied().collect();
+        ids.sort();
+        let tasks: Vec<TaskRef> = ids.into_iter().map(|id| TaskRef::new(self, id)).collect();
+        tasks.into_iter()
+    }
+
+    pub(crate) fn task_state(&self, id: usize) -> TaskState {
+        self.tasks.get(&id).map_or(TaskState::Finished, |info| info.state.get())
+    }
+
+    pub(crate) fn task_name(&self, id: usize) -> Option<&str> {
+        self.tasks.get(&id).and_then(|info| info.name.as_deref())
+    }
+
+    /// `<id>/<name>` of a task, as used in diagnostics.
+    pub(crate) fn task_label(&self, id: usize) -> String {
+        format!("{}/{}", id, self.task_name(id).unwrap_or("<unnamed>"))
+    }
+
     pub(crate) fn next_id(&mut self) -> usize {
         self.next_id += 1;
         self.next_id - 1
@@ -41,74 +269,366 @@ impl Runtime {
 
     /// Yield to the scheduler.
     /// Called by tasks to relinquish control.
+    #[track_caller]
     pub(crate) fn yield_to_base<R: 'static>(&mut self, packet: Box<Packet<R>>) {
+        no_yield::check_suspend();
+        cell::check_suspend();
+        if let Some(info) = self.tasks.get_mut(&self.cur_task) {
+            info.suspended_at = Some(Location::caller());
+            info.backtrace = self.capture_backtraces.then(Backtrace::force_capture);
+        }
         let base_cx = self.base_cx.take().expect("No base context set");
         let to_base = Transfer::new(base_cx, 0);
         let packet = unsafe { packet.raw_ptr() };
         let from_base = unsafe { to_base.context.resume(packet) };
         self.base_cx = Some(from_base.context);
+        if self.take_cancelled() {
+            panic::resume_unwind(Box::new(Cancelled));
+        }
+    }
+
+    /// Block the current task until another task wakes it up.
+    /// `primitive` identifies what the task waits on, for diagnostics and `wake_waiter`.
+    #[track_caller]
+    pub(crate) fn block(&mut self, cause: BlockCause, primitive: usize) {
+        self.waiting_on.insert(self.cur_task, primitive);
+        self.yield_to_base(Packet::<()>::block_on(cause));
+    }
+
+    /// Like `block`, but calls `abandon` if the task unwinds out of the wait instead,
+    /// i.e. when it is cancelled. By then it may still be queued on `primitive`, or it may
+    /// already have been handed the primitive by a wakeup, which it has to pass on.
+    #[track_caller]
+    pub(crate) fn block_or_abandon(&mut self, cause: BlockCause, primitive: usize, abandon: impl FnOnce()) {
+        struct Abandon<F: FnOnce()>(Option<F>);
+
+        impl<F: FnOnce()> Drop for Abandon<F> {
+            fn drop(&mut self) {
+                if let Some(abandon) = self.0.take() {
+                    abandon();
+                }
+            }
+        }
+
+        let mut guard = Abandon(Some(abandon));
+        self.block(cause, primitive);
+        guard.0 = None;
+    }
+
+    /// The current task is about to use a sync primitive which may block.
+    /// In simulation mode, this may yield in order to explore more interleavings,
+    /// unless the task must not suspend right now.
+    #[track_caller]
+    pub(crate) fn sync_point(&mut self, primitive: usize) {
+        self.record_access(primitive);
+        let task = self.cur_task;
+        if let Some(sim) = &mut self.sim
+            && task != usize::MAX
+            && !std::thread::panicking()
+            && !no_yield::is_active(task)
+            && !cell::holds_borrow(task)
+            && sim.should_yield()
+        {
+            self.yield_to_base(Packet::<()>::_yield());
+        }
+    }
+
+    /// Record that the current task touches a sync primitive, for the model checker.
+    pub(crate) fn record_access(&mut self, primitive: usize) {
+        if let Some(model) = &mut self.model {
+            model.record(Some(primitive));
+        }
+    }
+
+    /// Record that the current task may touch anything, for the model checker.
+    pub(crate) fn record_access_all(&mut self) {
+        if let Some(model) = &mut self.model {
+            model.record(None);
+        }
     }
 
+    /// Wake a blocked task. Tasks which are not blocked (anymore) are ignored,
+    /// e.g. a cancelled task that is still queued as a waiter of some primitive.
     pub(crate) fn wake_task(&mut self, id: usize) {
-        let mut task = self.blocking_tasks.remove(&id)
-            .expect("No task found for the given id");
-        task.trans_state(TaskState::Ready);
-        self.running_tasks.push_back(task);
+        if let Some(mut task) = self.blocking_tasks.remove(&id) {
+            let primitive = self.waiting_on.remove(&id).unwrap();
+            self.record_access(primitive);
+            task.trans_state(TaskState::Ready);
+            self.running_tasks.push_back(task);
+            trace::woken(id);
+            self.metrics.wakeups += 1;
+            self.metrics.record_queues(self.running_tasks.len(), self.blocking_tasks.len());
+        }
+    }
+
+    /// Wake a task popped from the waiter queue of `primitive`, if it still waits there,
+    /// or in a `select!` which may involve it. Returns whether it was woken.
+    /// Queues may hold stale ids, e.g. of a task cancelled while it waited, so a primitive
+    /// handing a resource to one waiter must keep popping until this succeeds,
+    /// or the wakeup is lost.
+    pub(crate) fn wake_waiter(&mut self, id: usize, primitive: usize) -> bool {
+        let waiting = self.waiting_on.get(&id) == Some(&primitive)
+            || self.blocking_tasks.get(&id).is_some_and(|task| task.state() == TaskState::Blocked(BlockCause::Select));
+        if waiting {
+            self.wake_task(id);
+        }
+        waiting
+    }
+
+    pub(crate) fn wake_joiners(&mut self, id: usize) {
+        self.record_access(id);
+        for joiner in self.joiners.remove(&id).unwrap_or_default() {
+            self.wake_waiter(joiner, id);
+        }
+    }
+
+    /// Make the task unwind the next time it is resumed, so that its destructors run.
+    pub(crate) fn cancel(&mut self, id: usize) {
+        self.cancelled.insert(id);
+        self.wake_task(id);
+    }
+
+    /// Whether the current task has been cancelled. Clears the mark,
+    /// so that a task which blocks again while unwinding is not cancelled twice.
+    pub(crate) fn take_cancelled(&mut self) -> bool {
+        self.cancelled.remove(&self.cur_task)
     }
 
-    pub(crate) fn spawn<F, R>(&mut self, future: F) -> JoinHandle<R>
+    /// Wake up the tasks whose timers are due.
+    pub(crate) fn fire_timers(&mut self) {
+        for task in self.timers.take_due() {
+            self.wake_task(task);
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.running_tasks.is_empty() && self.blocking_tasks.is_empty()
+    }
+
+    pub(crate) fn spawn<F, R>(&mut self, name: Option<String>, future: F) -> JoinHandle<R>
     where
         F: FnOnce() -> R + 'static,
         R: 'static,
     {
+        if let Some(max_tasks) = self.max_tasks {
+            assert!(self.tasks.len() < max_tasks, "cannot spawn more than {} tasks", max_tasks);
+        }
         let id = self.next_id();
-        let (task, init_cx) = Task::new(id, future);
+        trace::spawned(id);
+        let (task, init_cx) = Task::new(id, name.as_deref(), self.stack_size, self.track_stack_usage, future);
+        let cpu_time = task.cpu_time.clone();
+        let span = trace::task_span(id, name.as_deref());
+        #[cfg(feature = "watchdog")]
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.spawned(id, name.as_deref());
+        }
+        self.tasks.insert(id, TaskInfo {
+            name,
+            locals: Default::default(),
+            spawned_at: self.timers.now(),
+            suspended_at: None,
+            backtrace: None,
+            polls: 0,
+            busy: Duration::ZERO,
+            cpu_time: cpu_time.clone(),
+            state: task.state.clone(),
+            span,
+        });
+        let state = task.state.clone();
         let result = task.result.clone();
+        let stack_usage = task.stack_usage.clone();
         self.running_tasks.push_back(Box::new(task));
+        self.metrics.spawned += 1;
+        self.metrics.record_queues(self.running_tasks.len(), self.blocking_tasks.len());
         self.cxs.insert(id, init_cx);
         
-        JoinHandle { id, result }
+        JoinHandle { id, state, result, stack_usage, cpu_time }
     }
 
 
+    /// Run `future` as the root task and drive the runtime until it finishes.
+    /// Tasks which are still alive by then stay with the runtime,
+    /// until the next `block_on`, `shutdown` or until the runtime is dropped.
+    /// A panic inside any task is propagated to the caller,
+    /// and so are deadlocks and timeouts (see `Builder::timeout`), with a dump of the tasks.
     pub fn block_on<F, R>(&mut self, future: F) -> R
     where 
         F: FnOnce() -> R + 'static,
         R: 'static,
     {
-        let root_handle = self.spawn(future);
+        let _enter = enter(self);
+        let _seed = SeedReporter(self.seed());
+        let root_handle = self.spawn(None, future);
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        let timed_out = |_: &Self| deadline.is_some_and(|deadline| Instant::now() >= deadline);
 
-        loop {
-            match self.running_tasks.pop_front() {
+        let finished = self.run_until(|rt| root_handle.is_finished() || timed_out(rt), deadline);
+        if finished && !root_handle.is_finished() {
+            panic!("block_on timed out after {:?}: {}", self.timeout.unwrap(), self.dump());
+        }
+        if !finished {
+            let report = self.deadlock_report();
+            if let Some(hook) = &mut self.deadlock_hook {
+                hook(&report);
+            }
+            panic!("{}", report);
+        }
+
+        Rc::into_inner(root_handle.result)
+            .unwrap()
+            .into_inner()
+            .unwrap()
+    }
+
+    /// Tear down the tasks which are still alive.
+    /// Cancelled tasks unwind from the point where they are suspended, so their destructors run.
+    pub fn shutdown(&mut self, mode: ShutdownMode) {
+        let _enter = enter(self);
+        if let ShutdownMode::Drain(timeout) = mode {
+            let deadline = Instant::now() + timeout;
+            // A deadlock ends the draining early, as those tasks would never finish.
+            self.run_until(|rt| rt.is_idle() || Instant::now() >= deadline, Some(deadline));
+        }
+
+        let alive: Vec<usize> = self.running_tasks.iter()
+            .map(|task| task.id())
+            .chain(self.blocking_tasks.keys().copied())
+            .collect();
+        for id in alive {
+            self.cancel(id);
+        }
+        self.run_until(|rt| rt.running_tasks.is_empty(), None);
+
+        // Whatever is still blocked got stuck while unwinding.
+        // We can only free its stack without finishing the unwinding.
+        self.blocking_tasks.clear();
+        self.tasks.clear();
+        self.waiting_on.clear();
+        self.joiners.clear();
+        self.cxs.clear();
+        self.cancelled.clear();
+        self.timers.clear();
+    }
+
+    /// Run tasks until `done` holds. Returns `false` if the runtime is deadlocked instead,
+    /// i.e. every remaining task is blocked and no timer can wake any of them up.
+    /// Waiting for a timer never sleeps past `deadline`.
+    fn run_until(&mut self, mut done: impl FnMut(&Self) -> bool, deadline: Option<Instant>) -> bool {
+        while !done(self) {
+            if let Some(format) = signal::take_dump_request() {
+                let dump = self.dump();
+                match format {
+                    DumpFormat::Text => eprintln!("{}", dump),
+                    DumpFormat::Json => eprintln!("{}", dump.to_json()),
+                }
+            }
+            if !self.timers.is_empty() {
+                self.fire_timers();
+            }
+            match self.pick_next() {
                 Some(mut task) => {
                     assert!(matches!(task.state(), TaskState::Ready));
-                    self.cur_task = task.id();
+                    let id = task.id();
+                    self.cur_task = id;
+                    self.metrics.context_switches += 1;
+                    let started = Instant::now();
+                    let _span = self.tasks.get(&id).map(|info| trace::enter(&info.span));
+                    // The name is gone once the task finishes.
+                    let name = self.timeline.is_some().then(|| self.task_name(id).map(str::to_owned)).flatten();
+                    #[cfg(all(feature = "profile", target_os = "linux"))]
+                    crate::profile::switch_to(id, || self.task_name(id).map(str::to_owned));
+                    #[cfg(feature = "watchdog")]
+                    if let Some(watchdog) = &self.watchdog {
+                        watchdog.enter(id);
+                    }
                     task.resume();
+                    #[cfg(feature = "watchdog")]
+                    if let Some(watchdog) = &self.watchdog {
+                        watchdog.leave();
+                    }
+                    #[cfg(all(feature = "profile", target_os = "linux"))]
+                    crate::profile::switch_to(usize::MAX, || None);
+                    if let Some(info) = self.tasks.get_mut(&id) {
+                        info.polls += 1;
+                        info.busy += started.elapsed();
+                    }
+                    self.cur_task = usize::MAX;
+                    if let Some(timeline) = &mut self.timeline {
+                        let stop = match task.state() {
+                            TaskState::Blocked(cause) => StopReason::Block(cause),
+                            TaskState::Finished => StopReason::Finish,
+                            _ => StopReason::Yield,
+                        };
+                        timeline.record(id, name, started, stop);
+                    }
                     match task.state() {
                         TaskState::Finished => {},
-                        TaskState::Ready => self.running_tasks.push_back(task),
-                        TaskState::BlockOn(cause) => match cause {
-                            _ => {
-                                assert!(self.blocking_tasks.insert(task.id(), task).is_none())
-                            }
+                        TaskState::Ready => {
+                            trace::yielded(id);
+                            self.metrics.yields += 1;
+                            self.running_tasks.push_back(task);
+                        },
+                        TaskState::Blocked(cause) => {
+                            trace::blocked(id, cause, self.waiting_on.get(&id).copied().unwrap_or_default());
+                            self.metrics.record_block(cause);
+                            assert!(self.blocking_tasks.insert(task.id(), task).is_none())
                         },
                         TaskState::Running => unreachable!(),
                     }
+                    self.metrics.record_queues(self.running_tasks.len(), self.blocking_tasks.len());
                 },
                 None => {
                     // TODO: Handle blocking I/O tasks
-                    if !self.blocking_tasks.is_empty() {
-                        continue;
+                    // Only timers can wake up a task now, nothing else (I/O, other threads) can.
+                    let Some(next) = self.timers.next_deadline() else {
+                        return self.blocking_tasks.is_empty();
+                    };
+                    if self.timers.auto_advances() {
+                        self.timers.advance_to(next);
+                    } else if self.timers.is_paused() {
+                        // Nobody is left to advance the clock.
+                        return false;
+                    } else {
+                        let mut duration = next.saturating_duration_since(self.timers.now());
+                        if let Some(deadline) = deadline {
+                            duration = duration.min(deadline.saturating_duration_since(Instant::now()));
+                        }
+                        signal::sleep(duration);
                     }
-                    break;
                 },
             }
         }
+        true
+    }
 
-        Rc::into_inner(root_handle.result)
-            .unwrap()
-            .into_inner()
-            .unwrap()
+    fn pick_next(&mut self) -> Option<Box<dyn AnyTask>> {
+        if self.running_tasks.is_empty() {
+            return None;
+        }
+        let i = if let Some(model) = &mut self.model {
+            model.choose(self.running_tasks.iter().map(|task| task.id()).collect())
+        } else if let Some(sim) = &mut self.sim {
+            sim.pick(self.running_tasks.len())
+        } else {
+            0
+        };
+        self.running_tasks.remove(i)
+    }
+
+    fn deadlock_report(&self) -> DeadlockReport {
+        let mut tasks: Vec<BlockedTask> = self.blocking_tasks.values()
+            .map(|task| BlockedTask {
+                id: TaskId(task.id()),
+                name: self.task_name(task.id()).map(str::to_owned),
+                cause: match task.state() {
+                    TaskState::Blocked(cause) => cause,
+                    _ => unreachable!(),
+                },
+                primitive: self.waiting_on[&task.id()],
+            })
+            .collect();
+        tasks.sort_by_key(|task| task.id);
+        DeadlockReport { tasks, dump: self.dump() }
     }
 
     pub(crate) fn get_cur_cx(&mut self) -> Option<context::Context> {
@@ -127,4 +647,12 @@ impl Runtime {
     pub(crate) fn get_base_cx(&mut self) -> Option<context::Context> {
         self.base_cx.take()
     }
-}
\ No newline at end of file
+}
+
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        if !self.is_idle() {
+            self.shutdown(ShutdownMode::Cancel);
+        }
+    }
+}
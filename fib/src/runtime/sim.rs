@@ -0,0 +1,97 @@
+//! Deterministic simulation of scheduling decisions.
+//! In simulation mode the scheduler picks the next runnable task pseudo-randomly
+//! instead of in FIFO order, and may inject extra yields whenever a task is about to
+//! use a sync primitive. The whole run only depends on the seed, so a failing seed
+//! replays the exact same interleaving.
+
+use std::{env, hash::{BuildHasher, RandomState}, time::SystemTime};
+
+/// Environment variable overriding the seed of runtimes built without an explicit one.
+pub(crate) const SEED_VAR: &str = "FIB_SEED";
+
+pub(crate) struct Simulation {
+    pub(crate) seed: u64,
+    pub(crate) inject_yields: bool,
+    rng: Rng,
+}
+
+impl Simulation {
+    pub(crate) fn new(seed: Option<u64>, inject_yields: bool) -> Self {
+        let seed = seed.unwrap_or_else(|| match env::var(SEED_VAR) {
+            Ok(seed) => seed.parse().unwrap_or_else(|_| panic!("{} is not a valid seed: {:?}", SEED_VAR, seed)),
+            Err(_) => RandomState::new().hash_one(SystemTime::now()),
+        });
+        Self { seed, inject_yields, rng: Rng(seed) }
+    }
+
+    /// Index of the next task to run among `n` runnable ones.
+    pub(crate) fn pick(&mut self, n: usize) -> usize {
+        (self.rng.next() % n as u64) as usize
+    }
+
+    /// Whether to yield at a sync point.
+    pub(crate) fn should_yield(&mut self) -> bool {
+        self.inject_yields && self.rng.next() & 1 == 0
+    }
+}
+
+/// SplitMix64, small and good enough for shuffling schedules.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Prints the seed if the run panics, so that the failure can be replayed.
+pub(crate) struct SeedReporter(pub(crate) Option<u64>);
+
+impl Drop for SeedReporter {
+    fn drop(&mut self) {
+        if let Some(seed) = self.0
+            && std::thread::panicking()
+        {
+            eprintln!("fib: simulation failed with seed {} (replay with {}={})", seed, SEED_VAR, seed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::{runtime::Builder, sync::Mutex, task};
+
+    fn schedule(seed: u64) -> Vec<usize> {
+        let mut rt = Builder::new().seed(seed).inject_yields(true).build();
+        rt.block_on(|| {
+            let order = Rc::new(RefCell::new(vec![]));
+            let counter = Rc::new(Mutex::new(0));
+            let handles: Vec<_> = (0..4).map(|i| {
+                let (order, counter) = (order.clone(), counter.clone());
+                task::spawn(move || {
+                    for _ in 0..3 {
+                        *counter.lock() += 1;
+                        order.borrow_mut().push(i);
+                    }
+                })
+            }).collect();
+            for handle in handles {
+                handle.join();
+            }
+            assert_eq!(*counter.lock(), 12);
+            order.take()
+        })
+    }
+
+    #[test]
+    fn test_replay() {
+        assert_eq!(schedule(42), schedule(42));
+        assert!((0..16).any(|seed| schedule(seed) != schedule(42)));
+    }
+}
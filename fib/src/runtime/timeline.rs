@@ -0,0 +1,93 @@
+//! Recording of which task held the thread when, see `Runtime::start_timeline`.
+
+use std::{collections::VecDeque, fmt::Write as _, io, time::{Duration, Instant}};
+
+use crate::{runtime::dump::json_str, task::{BlockCause, TaskId}, utils::task_label};
+
+/// The latest run intervals of every task since the recording started, oldest first.
+#[derive(Debug, Clone)]
+pub struct Timeline {
+    started: Instant,
+    capacity: usize,
+    pub intervals: VecDeque<RunInterval>,
+    /// Older intervals discarded to keep at most `capacity` of them.
+    pub dropped: u64,
+}
+
+/// A stretch of time during which a task was running.
+#[derive(Debug, Clone)]
+pub struct RunInterval {
+    pub task: TaskId,
+    pub name: Option<String>,
+    /// Offset from the start of the recording.
+    pub start: Duration,
+    pub duration: Duration,
+    pub stop: StopReason,
+}
+
+/// Why a task gave the thread back to the scheduler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Yield,
+    Block(BlockCause),
+    Finish,
+}
+
+impl Timeline {
+    /// Intervals kept by `Runtime::start_timeline`, a few MB worth.
+    pub const DEFAULT_CAPACITY: usize = 100_000;
+
+    pub(crate) fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "timeline capacity must be positive");
+        Self { started: Instant::now(), capacity, intervals: VecDeque::new(), dropped: 0 }
+    }
+
+    pub(crate) fn record(&mut self, task: usize, name: Option<String>, start: Instant, stop: StopReason) {
+        if self.intervals.len() == self.capacity {
+            self.intervals.pop_front();
+            self.dropped += 1;
+        }
+        self.intervals.push_back(RunInterval {
+            task: TaskId(task),
+            name,
+            start: start - self.started,
+            duration: start.elapsed(),
+            stop,
+        });
+    }
+
+    /// Render the timeline in the Chrome Trace Event format, which Perfetto and
+    /// `chrome://tracing` can open. Every run interval is a complete event on a single
+    /// thread track, as all tasks share one kernel thread.
+    pub fn to_chrome_trace(&self) -> String {
+        let mut json = String::from(concat!(
+            r#"{"displayTimeUnit":"ns","traceEvents":["#,
+            r#"{"name":"thread_name","ph":"M","pid":1,"tid":1,"args":{"name":"fib scheduler"}}"#,
+        ));
+        for interval in &self.intervals {
+            let label = task_label(interval.task.0, interval.name.as_deref()).to_string();
+            let stop = match interval.stop {
+                StopReason::Yield => "yield".to_string(),
+                StopReason::Block(cause) => format!("block {:?}", cause),
+                StopReason::Finish => "finish".to_string(),
+            };
+            json.push_str(",{\"name\":");
+            json_str(&mut json, Some(&label));
+            write!(
+                json,
+                r#","cat":"fib","ph":"X","pid":1,"tid":1,"ts":{:.3},"dur":{:.3},"args":{{"task":{},"stop":"{}"}}}}"#,
+                interval.start.as_secs_f64() * 1e6,
+                interval.duration.as_secs_f64() * 1e6,
+                interval.task,
+                stop,
+            ).unwrap();
+        }
+        json.push_str("]}");
+        json
+    }
+
+    /// Write `to_chrome_trace` to e.g. a file.
+    pub fn write_chrome_trace(&self, mut out: impl io::Write) -> io::Result<()> {
+        out.write_all(self.to_chrome_trace().as_bytes())
+    }
+}
@@ -0,0 +1,192 @@
+//! Integration with the `tracing` crate, behind the `tracing` feature.
+//! Every task gets a `fib.task` span, a child of the span which was current when it was spawned.
+//! The scheduler enters it whenever it switches into the task, so that everything the task
+//! logs is attributed to it, and emits trace-level events for spawn, yield, block, wake and finish.
+//! Without the feature all of this compiles to nothing.
+//!
+//! Spans entered by a task with `Span::enter` must not be held across a yield point,
+//! as the subscriber's stack of entered spans belongs to the thread, not to the fiber.
+
+use crate::task::BlockCause;
+
+#[cfg(feature = "tracing")]
+pub(crate) type TaskSpan = tracing::Span;
+#[cfg(feature = "tracing")]
+pub(crate) type Entered = tracing::span::EnteredSpan;
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct TaskSpan;
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct Entered;
+
+#[cfg(feature = "tracing")]
+pub(crate) fn task_span(id: usize, name: Option<&str>) -> TaskSpan {
+    // Contextual, so the span which is current right now becomes the parent.
+    tracing::trace_span!("fib.task", task = id, name = name.unwrap_or("<unnamed>"))
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn task_span(_id: usize, _name: Option<&str>) -> TaskSpan {
+    TaskSpan
+}
+
+/// Enter the span of a task which the scheduler is about to switch into.
+#[cfg(feature = "tracing")]
+pub(crate) fn enter(span: &TaskSpan) -> Entered {
+    span.clone().entered()
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn enter(_span: &TaskSpan) -> Entered {
+    Entered
+}
+
+macro_rules! event {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(target: "fib::runtime", $($arg)*);
+    };
+}
+
+pub(crate) fn spawned(id: usize) {
+    event!(task = id, "spawn");
+}
+
+pub(crate) fn yielded(id: usize) {
+    event!(task = id, "yield");
+}
+
+pub(crate) fn blocked(id: usize, cause: BlockCause, primitive: usize) {
+    event!(task = id, ?cause, primitive, "block");
+}
+
+pub(crate) fn woken(id: usize) {
+    event!(task = id, "wake");
+}
+
+pub(crate) fn finished(id: usize) {
+    event!(task = id, "finish");
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use std::sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex};
+
+    use tracing::{field::{Field, Visit}, span, Event, Metadata, Subscriber};
+
+    use crate::{runtime::Builder, task};
+
+    /// Id, `task` field and parent of a span.
+    type SpanRecord = (u64, Option<u64>, Option<u64>);
+    /// Message of an event and `task` field of the span it happened in.
+    type EventRecord = (String, Option<u64>);
+
+    /// Records every span and event, along with the `task` field of the innermost entered span.
+    #[derive(Default)]
+    struct Recorder {
+        next_id: AtomicU64,
+        spans: Arc<Mutex<Vec<SpanRecord>>>,
+        stack: Mutex<Vec<u64>>,
+        events: Arc<Mutex<Vec<EventRecord>>>,
+    }
+
+    #[derive(Default)]
+    struct Fields {
+        message: String,
+        task: Option<u64>,
+    }
+
+    impl Visit for Fields {
+        fn record_u64(&mut self, field: &Field, value: u64) {
+            if field.name() == "task" {
+                self.task = Some(value);
+            }
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "message" {
+                self.message = format!("{:?}", value);
+            }
+        }
+    }
+
+    impl Recorder {
+        fn task_of(&self, span: u64) -> Option<u64> {
+            self.spans.lock().unwrap().iter().find(|(id, ..)| *id == span).and_then(|(_, task, _)| *task)
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, attrs: &span::Attributes<'_>) -> span::Id {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+            let mut fields = Fields::default();
+            attrs.record(&mut fields);
+            let parent = attrs.parent().map(span::Id::into_u64)
+                .or_else(|| attrs.is_contextual().then(|| self.stack.lock().unwrap().last().copied()).flatten());
+            self.spans.lock().unwrap().push((id, fields.task, parent));
+            span::Id::from_u64(id)
+        }
+
+        fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut fields = Fields::default();
+            event.record(&mut fields);
+            let current = self.stack.lock().unwrap().last().copied();
+            let task = current.and_then(|span| self.task_of(span));
+            self.events.lock().unwrap().push((fields.message, task));
+        }
+
+        fn enter(&self, span: &span::Id) {
+            self.stack.lock().unwrap().push(span.into_u64());
+        }
+
+        fn exit(&self, span: &span::Id) {
+            let mut stack = self.stack.lock().unwrap();
+            let i = stack.iter().rposition(|id| *id == span.into_u64()).unwrap();
+            stack.remove(i);
+        }
+    }
+
+    #[test]
+    fn test_spans() {
+        let recorder = Recorder::default();
+        let events = recorder.events.clone();
+        let spans = recorder.spans.clone();
+        tracing::subscriber::with_default(recorder, || {
+            let request = tracing::info_span!("request");
+            let _request = request.enter();
+            let mut rt = Builder::new().build();
+            rt.block_on(|| {
+                let child = task::spawn(|| {
+                    tracing::info!("in child");
+                    task::yield_now();
+                });
+                tracing::info!("in root");
+                child.join();
+            });
+        });
+
+        let events = events.lock().unwrap().clone();
+        let expected: Vec<EventRecord> = [
+            ("spawn", None),
+            ("spawn", Some(0)),
+            ("in root", Some(0)),
+            ("block", Some(0)),
+            ("in child", Some(1)),
+            ("yield", Some(1)),
+            ("finish", Some(1)),
+            ("wake", Some(1)),
+            ("finish", Some(0)),
+        ].into_iter().map(|(message, task)| (message.to_string(), task)).collect();
+        assert_eq!(events, expected);
+        // request <- task 0 <- task 1
+        assert_eq!(*spans.lock().unwrap(), [(1, None, None), (2, Some(0), Some(1)), (3, Some(1), Some(2))]);
+    }
+}
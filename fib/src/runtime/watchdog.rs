@@ -0,0 +1,254 @@
+//! Detecting tasks which hog the thread, see `Runtime::start_watchdog`.
+//!
+//! The scheduler publishes which task it switched into and when through a seqlock.
+//! A watchdog thread polls them, and once a task has run for longer than the threshold,
+//! it interrupts the runtime thread with a signal whose handler walks the stack of that task.
+
+use std::{cell::Cell, collections::HashMap, ffi::c_void, ptr, sync::{atomic::{self, AtomicBool, AtomicU64, AtomicUsize, Ordering}, Arc, Mutex, Once, OnceLock}, thread::{self, JoinHandle}, time::{Duration, Instant}};
+
+use crate::{task::stack, utils};
+
+/// Deepest stack captured, deeper frames are cut off.
+const MAX_DEPTH: usize = 64;
+/// Signal used to capture backtraces. Its default action is to do nothing.
+const SIGNAL: libc::c_int = libc::SIGURG;
+/// How long to wait for the runtime thread to capture a backtrace.
+const CAPTURE_TIMEOUT: Duration = Duration::from_millis(100);
+/// Not captured yet, as a depth.
+const PENDING: usize = usize::MAX;
+
+static HANDLER: Once = Once::new();
+/// The handler installed before ours, which gets every `SIGNAL` not sent by the watchdog.
+static PREV_HANDLER: OnceLock<libc::sigaction> = OnceLock::new();
+
+thread_local! {
+    /// The watchdog of the task running on this thread, read by the signal handler.
+    static WATCHED: Cell<*const Shared> = const { Cell::new(ptr::null()) };
+}
+
+pub(crate) struct Watchdog {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    threshold: Duration,
+    epoch: Instant,
+    runtime_thread: libc::pthread_t,
+    /// The task being run, `usize::MAX` while the scheduler runs.
+    task: AtomicUsize,
+    /// Sequence number of `task` and `since`: odd while the scheduler writes them,
+    /// and even again, i.e. a new stint, once they are consistent.
+    stint: AtomicU64,
+    /// When the current stint started, in nanoseconds since `epoch`.
+    since: AtomicU64,
+    /// The stint whose backtrace is requested from the signal handler.
+    target: AtomicU64,
+    depth: AtomicUsize,
+    frames: [AtomicUsize; MAX_DEPTH],
+    stalls: AtomicU64,
+    /// Names of the named live tasks, as the runtime itself is out of reach of the watchdog.
+    names: Mutex<HashMap<usize, String>>,
+    stop: AtomicBool,
+}
+
+impl Watchdog {
+    /// Start watching the calling thread, which must be the thread of the runtime.
+    pub(crate) fn start(threshold: Duration, names: HashMap<usize, String>) -> Self {
+        install_handler();
+        let shared = Arc::new(Shared {
+            threshold,
+            epoch: Instant::now(),
+            runtime_thread: unsafe { libc::pthread_self() },
+            task: AtomicUsize::new(usize::MAX),
+            stint: AtomicU64::new(0),
+            since: AtomicU64::new(0),
+            target: AtomicU64::new(0),
+            depth: AtomicUsize::new(PENDING),
+            frames: [const { AtomicUsize::new(0) }; MAX_DEPTH],
+            stalls: AtomicU64::new(0),
+            names: Mutex::new(names),
+            stop: AtomicBool::new(false),
+        });
+        let watched = shared.clone();
+        let thread = thread::Builder::new()
+            .name("fib-watchdog".to_string())
+            .spawn(move || watch(&watched))
+            .expect("failed to spawn the watchdog thread");
+        Self { shared, thread: Some(thread) }
+    }
+
+    /// Times a task ran for longer than the threshold without returning to the scheduler.
+    pub(crate) fn stalls(&self) -> u64 {
+        self.shared.stalls.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn spawned(&self, id: usize, name: Option<&str>) {
+        if let Some(name) = name {
+            self.shared.names.lock().unwrap().insert(id, name.to_string());
+        }
+    }
+
+    pub(crate) fn finished(&self, id: usize) {
+        self.shared.names.lock().unwrap().remove(&id);
+    }
+
+    /// The scheduler is about to switch into `id`.
+    pub(crate) fn enter(&self, id: usize) {
+        WATCHED.set(&*self.shared);
+        self.shared.publish(id);
+    }
+
+    /// The scheduler is back from the task it switched into.
+    pub(crate) fn leave(&self) {
+        self.shared.publish(usize::MAX);
+        WATCHED.set(ptr::null());
+    }
+}
+
+impl Shared {
+    /// Start a new stint of `task`. Only ever called from the runtime thread.
+    fn publish(&self, task: usize) {
+        let stint = self.stint.load(Ordering::Relaxed);
+        self.stint.store(stint + 1, Ordering::Relaxed);
+        atomic::fence(Ordering::Release);
+        self.task.store(task, Ordering::Relaxed);
+        self.since.store(self.epoch.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        self.stint.store(stint + 2, Ordering::Release);
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        // The watchdog may be stopped by the task it watches.
+        if ptr::eq(WATCHED.get(), &*self.shared) {
+            WATCHED.set(ptr::null());
+        }
+        self.shared.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            thread.join().unwrap();
+        }
+    }
+}
+
+fn watch(shared: &Shared) {
+    let interval = (shared.threshold / 4).max(Duration::from_millis(1));
+    let mut reported = None;
+    while !shared.stop.load(Ordering::Relaxed) {
+        thread::park_timeout(interval);
+        let stint = shared.stint.load(Ordering::Acquire);
+        let task = shared.task.load(Ordering::Relaxed);
+        let since = Duration::from_nanos(shared.since.load(Ordering::Relaxed));
+        atomic::fence(Ordering::Acquire);
+        // `task` and `since` belong to `stint` unless it was odd or has moved on since.
+        if stint % 2 == 1 || shared.stint.load(Ordering::Relaxed) != stint {
+            continue;
+        }
+        if task == usize::MAX || reported == Some(stint) {
+            continue;
+        }
+        let running = shared.epoch.elapsed().saturating_sub(since);
+        if running < shared.threshold {
+            continue;
+        }
+        reported = Some(stint);
+        shared.stalls.fetch_add(1, Ordering::Relaxed);
+
+        let name = shared.names.lock().unwrap().get(&task).cloned();
+        let mut report = format!(
+            "fib: {} has been running for {:?} without yielding",
+            utils::task_label(task, name.as_deref()), running,
+        );
+        match capture(shared, stint) {
+            Some(frames) => {
+                report.push_str(", at:");
+                for (i, frame) in utils::symbolize(&frames, "handle_signal", &mut HashMap::new()).iter().enumerate() {
+                    report.push_str(&format!("\n  {:>3}: {}", i, frame));
+                }
+            },
+            None => report.push_str(", it yielded before a backtrace could be captured"),
+        }
+        eprintln!("{}", report);
+    }
+}
+
+/// Interrupt the runtime thread in order to walk the stack of the task running in `stint`.
+fn capture(shared: &Shared, stint: u64) -> Option<Vec<usize>> {
+    shared.depth.store(PENDING, Ordering::Relaxed);
+    shared.target.store(stint, Ordering::Release);
+    unsafe {
+        libc::pthread_kill(shared.runtime_thread, SIGNAL);
+    }
+    let deadline = Instant::now() + CAPTURE_TIMEOUT;
+    loop {
+        match shared.depth.load(Ordering::Acquire) {
+            PENDING if Instant::now() < deadline => thread::sleep(Duration::from_millis(1)),
+            PENDING | 0 => return None,
+            depth => return Some(shared.frames[..depth].iter().map(|frame| frame.load(Ordering::Relaxed)).collect()),
+        }
+    }
+}
+
+fn install_handler() {
+    stack::install_alt_stack();
+    HANDLER.call_once(|| unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle_signal as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut action.sa_mask);
+        let mut prev: libc::sigaction = std::mem::zeroed();
+        libc::sigaction(SIGNAL, &action, &mut prev);
+        let _ = PREV_HANDLER.set(prev);
+    });
+}
+
+/// Pass a signal the watchdog did not send on to the handler installed before ours.
+/// `SIGURG` is ignored by default, so there is nothing to do without one.
+unsafe fn forward(signum: libc::c_int, info: *mut libc::siginfo_t, ucontext: *mut c_void) {
+    let Some(prev) = PREV_HANDLER.get() else { return };
+    if prev.sa_sigaction == libc::SIG_DFL || prev.sa_sigaction == libc::SIG_IGN {
+        return;
+    }
+    unsafe {
+        if prev.sa_flags & libc::SA_SIGINFO != 0 {
+            let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut c_void) = std::mem::transmute(prev.sa_sigaction);
+            handler(signum, info, ucontext);
+        } else {
+            let handler: extern "C" fn(libc::c_int) = std::mem::transmute(prev.sa_sigaction);
+            handler(signum);
+        }
+    }
+}
+
+extern "C" fn handle_signal(signum: libc::c_int, info: *mut libc::siginfo_t, ucontext: *mut c_void) {
+    // The watchdog signals the runtime thread with `pthread_kill`, anything else,
+    // e.g. out-of-band data on a socket owned by the process, is someone else's.
+    let sent_by_watchdog = unsafe { (*info).si_code == libc::SI_TKILL && (*info).si_pid() == libc::getpid() };
+    let shared = WATCHED.get();
+    if !sent_by_watchdog || shared.is_null() {
+        unsafe { forward(signum, info, ucontext) };
+        return;
+    }
+    // Nothing in here allocates. The walk goes through libgcc's unwinder though, which takes
+    // the lock of `dl_iterate_phdr`, so a task stalled inside a panic or a `Backtrace`
+    // capture of its own may deadlock here.
+    let shared = unsafe { &*shared };
+    if shared.stint.load(Ordering::Relaxed) != shared.target.load(Ordering::Acquire) {
+        shared.depth.store(0, Ordering::Release);
+        return;
+    }
+    let mut depth = 0;
+    unsafe {
+        backtrace::trace_unsynchronized(|frame| {
+            // The outermost frame of a fiber returns to nowhere.
+            if frame.ip().is_null() {
+                return false;
+            }
+            shared.frames[depth].store(frame.ip() as usize, Ordering::Relaxed);
+            depth += 1;
+            depth < MAX_DEPTH
+        });
+    }
+    shared.depth.store(depth, Ordering::Release);
+}
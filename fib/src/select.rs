@@ -0,0 +1,129 @@
+//! Waiting on several primitives at once, see the `select!` macro.
+//! A selecting fiber registers as a waiter on every branch, is woken up by the first one
+//! to become ready, and deregisters from the others before the chosen branch runs.
+//! Supported sources: `mpsc::Receiver`, `oneshot::Receiver`, `Notify` and `time::Sleep`.
+//! fib has no I/O primitives yet, once it does they can implement `Selectable` too.
+
+use std::{any::Any, cell::Cell, panic::{self, AssertUnwindSafe}};
+
+use crate::{runtime::runtime, task::BlockCause};
+
+/// Something a fiber can wait on as part of a `select!`.
+pub trait Selectable {
+    type Output;
+
+    /// Complete right away if ready, without blocking.
+    fn try_select(&mut self) -> Option<Self::Output>;
+
+    /// Make sure the current task is woken up once `try_select` may succeed.
+    /// May be called again after a wakeup which did not lead to a completion.
+    fn register(&mut self);
+
+    /// Undo `register`. Called for every branch once the select is done,
+    /// whether it has registered or not. A notification delivered to the current task
+    /// but not consumed by `try_select` must be passed on.
+    fn deregister(&mut self);
+}
+
+thread_local! {
+    static ROTATION: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Order in which to try the branches. Unless biased, the first branch rotates between
+/// selects, so that a branch which is always ready cannot starve the others.
+#[doc(hidden)]
+pub fn __order(branches: usize, biased: bool) -> impl Iterator<Item = usize> {
+    let start = if biased { 0 } else { ROTATION.replace(ROTATION.get().wrapping_add(1)) };
+    (0..branches).map(move |i| (start + i) % branches)
+}
+
+/// Block until some branch wakes the current task up. A cancelled task is handed
+/// the unwinding payload, so that it can deregister before unwinding further.
+#[doc(hidden)]
+pub fn __block(branches: usize) -> Result<(), Box<dyn Any + Send>> {
+    panic::catch_unwind(AssertUnwindSafe(|| runtime().block(BlockCause::Select, branches)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, time::Duration};
+
+    use crate::{sync::{mpsc, oneshot, Notify}, task, time::{self, Sleep}};
+
+    #[fib::test]
+    fn test_first_ready() {
+        let (_tx1, rx1) = mpsc::channel::<i32>();
+        let (tx2, rx2) = mpsc::channel();
+        let shutdown = Notify::new();
+        task::spawn(move || {
+            task::yield_now();
+            tx2.send("hello").unwrap();
+        });
+        let msg = fib::select! {
+            _ = &rx1 => unreachable!(),
+            msg = &rx2 => msg.unwrap(),
+            () = &shutdown => unreachable!(),
+        };
+        assert_eq!(msg, "hello");
+        // Deregistered, so the channel can be received from again.
+        assert!(rx1.try_recv().is_err());
+    }
+
+    #[fib::test(paused_time)]
+    fn test_timeout() {
+        let (_tx, mut rx) = oneshot::channel::<()>();
+        let start = time::now();
+        let timed_out = fib::select! {
+            _ = &mut rx => false,
+            () = Sleep::new(Duration::from_secs(30)) => true,
+        };
+        assert!(timed_out);
+        assert_eq!(time::now() - start, Duration::from_secs(30));
+    }
+
+    #[fib::test]
+    fn test_default_and_biased() {
+        let (tx, rx) = mpsc::channel();
+        let notify = Notify::new();
+        let ready = fib::select! {
+            _ = &rx => true,
+            default => false,
+        };
+        assert!(!ready);
+
+        tx.send(1).unwrap();
+        notify.notify_one();
+        for _ in 0..2 {
+            let first = fib::select! {
+                biased;
+                _ = &rx => true,
+                () = &notify => false,
+            };
+            assert!(first);
+            tx.send(1).unwrap();
+        }
+    }
+
+    #[fib::test]
+    fn test_notification_passed_on() {
+        let (tx, rx) = mpsc::channel();
+        let notify = Rc::new(Notify::new());
+        let waiter = task::spawn({
+            let notify = notify.clone();
+            move || {
+                fib::select! {
+                    biased;
+                    _ = &rx => {},
+                    () = &*notify => unreachable!(),
+                }
+            }
+        });
+        task::yield_now();
+        // Both fire before the selecting task runs again, it takes the message.
+        tx.send(1).unwrap();
+        notify.notify_one();
+        waiter.join();
+        // The notification it did not consume is not lost.
+        notify.wait();
+    }
+}
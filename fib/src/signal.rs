@@ -0,0 +1,59 @@
+//! Dumping the tasks on a signal, for when a process hangs in production.
+
+use std::{sync::{atomic::{AtomicBool, AtomicU8, Ordering}, Once}, time::Duration};
+
+static HANDLER: Once = Once::new();
+static DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+static FORMAT: AtomicU8 = AtomicU8::new(DumpFormat::Text as u8);
+
+/// How a dump requested by a signal is rendered, see `Runtime::dump`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DumpFormat {
+    Text,
+    Json,
+}
+
+/// Print `Runtime::dump` to stderr whenever the process receives `SIGUSR1`.
+/// A signal handler must not touch the runtime, so the dump is printed by the next runtime
+/// which gets to schedule a task. A task which never yields therefore delays it.
+/// A runtime sleeping until its next timer wakes up early if the signal is delivered
+/// to its thread, but the kernel may pick any thread of the process, in which case
+/// the dump waits for the timer. Block `SIGUSR1` in the other threads to avoid that.
+pub fn dump_on_sigusr1(format: DumpFormat) {
+    FORMAT.store(format as u8, Ordering::Relaxed);
+    HANDLER.call_once(|| unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle_sigusr1 as *const () as usize;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut());
+    });
+}
+
+extern "C" fn handle_sigusr1(_signum: libc::c_int) {
+    DUMP_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Sleep for `duration` like `thread::sleep`, but return early when a signal interrupts it,
+/// so that the scheduler notices a dump request right away.
+pub(crate) fn sleep(duration: Duration) {
+    let request = libc::timespec {
+        tv_sec: duration.as_secs() as libc::time_t,
+        tv_nsec: duration.subsec_nanos() as libc::c_long,
+    };
+    unsafe {
+        libc::nanosleep(&request, std::ptr::null_mut());
+    }
+}
+
+/// Whether a dump has been requested since the last call, and in which format.
+pub(crate) fn take_dump_request() -> Option<DumpFormat> {
+    if !DUMP_REQUESTED.swap(false, Ordering::Relaxed) {
+        return None;
+    }
+    Some(match FORMAT.load(Ordering::Relaxed) {
+        0 => DumpFormat::Text,
+        _ => DumpFormat::Json,
+    })
+}
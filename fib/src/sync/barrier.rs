@@ -1,6 +1,6 @@
 use std::{cell::RefCell, collections::VecDeque, rc::Rc};
 
-use crate::{runtime::runtime, task::packet::Packet};
+use crate::{runtime::runtime, task::BlockCause};
 
 struct BarrierCore {
     threshold: usize,
@@ -32,21 +32,23 @@ impl Barrier {
         }
     }
 
+    #[track_caller]
     pub fn wait(&self) -> BarrierWaitResult {
+        runtime().sync_point(Rc::as_ptr(&self.core) as usize);
         let mut core = self.core.borrow_mut();
         core.count -= 1;
         if core.count == 0 {
             core.count = core.threshold;
             let rt = runtime();
             while let Some(waiter) = core.waiters.pop_front() {
-                rt.wake_task(waiter);
+                rt.wake_waiter(waiter, Rc::as_ptr(&self.core) as usize);
             }
             BarrierWaitResult { is_leader: true }
         } else {
             let rt = runtime();
             core.waiters.push_back(rt.cur_task());
             drop(core);
-            rt.yield_to_base(Packet::<()>::block_on(crate::task::BlockCause::Barrier));            
+            rt.block(BlockCause::Barrier, Rc::as_ptr(&self.core) as usize);
             BarrierWaitResult { is_leader: false }
         }
     }
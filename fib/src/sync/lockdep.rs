@@ -0,0 +1,272 @@
+//! Lock-order checking for `Mutex`, `RwLock` and `Semaphore`, in the spirit of Linux' lockdep.
+//! Once enabled, every acquisition made while other locks are held adds an edge to a
+//! lock-order graph. Acquiring locks in an order which closes a cycle in that graph is
+//! reported as a potential deadlock, even if the run at hand did not deadlock.
+//! Locks are identified by address, so a lock freed and reallocated at the same address
+//! is considered the same lock.
+
+use std::{cell::{Cell, RefCell}, collections::{HashMap, HashSet}, fmt::Display, panic::Location};
+
+use crate::{runtime::runtime, task::TaskId};
+
+thread_local! {
+    static ENABLED: Cell<bool> = const { Cell::new(false) };
+    static STATE: RefCell<LockDep> = RefCell::new(LockDep::default());
+}
+
+#[derive(Default)]
+struct LockDep {
+    /// Locks currently held by each task, in acquisition order.
+    held: HashMap<usize, Vec<Held>>,
+    /// `(a, b)` means `b` has been acquired while holding `a`.
+    edges: HashMap<(usize, usize), Edge>,
+    reported: HashSet<(usize, usize)>,
+    violations: Vec<LockOrderViolation>,
+}
+
+struct Held {
+    lock: usize,
+    site: &'static Location<'static>,
+    /// Guards sharing this acquisition, as permits split off a semaphore permit.
+    guards: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Edge {
+    first_at: &'static Location<'static>,
+    second_at: &'static Location<'static>,
+}
+
+/// Two locks acquired in opposite orders, which can deadlock.
+#[derive(Debug, Clone)]
+pub struct LockOrderViolation {
+    pub task: TaskId,
+    /// The lock being acquired.
+    pub acquired: usize,
+    pub acquired_at: &'static Location<'static>,
+    /// The lock held while acquiring `acquired`.
+    pub held: usize,
+    pub held_at: &'static Location<'static>,
+    /// Where `acquired` was previously taken first, before `held` (possibly through other locks).
+    pub prior_first_at: &'static Location<'static>,
+    pub prior_second_at: &'static Location<'static>,
+}
+
+impl Display for LockOrderViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "potential deadlock: inconsistent lock order in task {}", self.task)?;
+        writeln!(f, "  acquiring {:#x} at {}", self.acquired, self.acquired_at)?;
+        writeln!(f, "  while holding {:#x} acquired at {}", self.held, self.held_at)?;
+        writeln!(f, "  but previously {:#x} was acquired at {}", self.acquired, self.prior_first_at)?;
+        write!(f, "  before {:#x} at {}", self.held, self.prior_second_at)
+    }
+}
+
+/// Start recording lock acquisitions on this thread.
+pub fn enable() {
+    ENABLED.set(true);
+}
+
+/// Stop recording and forget the lock-order graph.
+pub fn disable() {
+    ENABLED.set(false);
+    STATE.with(|state| *state.borrow_mut() = LockDep::default());
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.get()
+}
+
+/// Violations reported so far.
+pub fn violations() -> Vec<LockOrderViolation> {
+    STATE.with(|state| state.borrow().violations.clone())
+}
+
+/// Record that the current task has acquired `lock`.
+/// Non-blocking acquisitions cannot deadlock themselves, so they are not checked,
+/// but the lock still counts as held for later acquisitions.
+/// Blocking ones are recorded before blocking, so that a deadlock they run into is reported.
+/// A task unwinding out of the wait must `release` the lock again.
+pub(crate) fn acquire(lock: usize, site: &'static Location<'static>, blocking: bool) {
+    if !is_enabled() {
+        return;
+    }
+    let task = runtime().cur_task();
+    STATE.with(|state| {
+        let state = &mut *state.borrow_mut();
+        let held = state.held.entry(task).or_default();
+        if blocking {
+            for prev in held.iter().filter(|prev| prev.lock != lock) {
+                if !state.reported.contains(&(prev.lock, lock))
+                    && let Some(edge) = find_path(&state.edges, lock, prev.lock)
+                {
+                    state.reported.insert((prev.lock, lock));
+                    let violation = LockOrderViolation {
+                        task: TaskId(task),
+                        acquired: lock,
+                        acquired_at: site,
+                        held: prev.lock,
+                        held_at: prev.site,
+                        prior_first_at: edge.first_at,
+                        prior_second_at: edge.second_at,
+                    };
+                    eprintln!("{}", violation);
+                    state.violations.push(violation);
+                }
+                state.edges.entry((prev.lock, lock)).or_insert(Edge {
+                    first_at: prev.site,
+                    second_at: site,
+                });
+            }
+        }
+        held.push(Held { lock, site, guards: 1 });
+    });
+}
+
+/// Record that a new guard shares the latest acquisition of `lock` by the current task.
+/// The lock is only considered released once every guard has been released.
+pub(crate) fn share(lock: usize) {
+    if !is_enabled() {
+        return;
+    }
+    let task = runtime().cur_task();
+    STATE.with(|state| {
+        if let Some(held) = state.borrow_mut().held.get_mut(&task)
+            && let Some(held) = held.iter_mut().rfind(|held| held.lock == lock)
+        {
+            held.guards += 1;
+        }
+    });
+}
+
+/// Record that the current task has released a guard of `lock`.
+pub(crate) fn release(lock: usize) {
+    if !is_enabled() {
+        return;
+    }
+    let task = runtime().cur_task();
+    STATE.with(|state| {
+        if let Some(held) = state.borrow_mut().held.get_mut(&task)
+            && let Some(i) = held.iter().rposition(|held| held.lock == lock)
+        {
+            held[i].guards -= 1;
+            if held[i].guards == 0 {
+                held.remove(i);
+            }
+        }
+    });
+}
+
+/// Look for a path `from` ->* `to` in the lock-order graph,
+/// returning its first edge if there is one.
+fn find_path(edges: &HashMap<(usize, usize), Edge>, from: usize, to: usize) -> Option<Edge> {
+    let mut visited = HashSet::new();
+    let mut stack: Vec<(usize, Option<Edge>)> = vec![(from, None)];
+    while let Some((lock, first)) = stack.pop() {
+        if !visited.insert(lock) {
+            continue;
+        }
+        for (&(a, b), &edge) in edges {
+            if a == lock {
+                let first = first.or(Some(edge));
+                if b == to {
+                    return first;
+                }
+                stack.push((b, first));
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::{runtime::{runtime, Builder}, sync::{Mutex, Semaphore}, task};
+
+    use super::*;
+
+    #[test]
+    fn test_abba() {
+        enable();
+        runtime().block_on(|| {
+            let a = Rc::new(Mutex::new(()));
+            let b = Rc::new(Semaphore::new(1));
+            let (a1, b1) = (a.clone(), b.clone());
+            task::spawn(move || {
+                let _a = a1.lock();
+                let _b = b1.acquire();
+            }).join();
+            assert!(violations().is_empty());
+            task::spawn(move || {
+                let _b = b.acquire();
+                let _a = a.lock();
+            }).join();
+        });
+        let violations = violations();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].prior_first_at.line() + 1, violations[0].prior_second_at.line());
+        assert_eq!(violations[0].held_at.line() + 1, violations[0].acquired_at.line());
+        disable();
+    }
+
+    #[test]
+    fn test_split_permits() {
+        enable();
+        runtime().block_on(|| {
+            let a = Rc::new(Semaphore::new(4));
+            let b = Rc::new(Mutex::new(()));
+            let (a1, b1) = (a.clone(), b.clone());
+            task::spawn(move || {
+                let mut permit = a1.acquire().unwrap();
+                permit.merge(a1.acquire().unwrap());
+                permit.merge(a1.acquire().unwrap());
+                let split = permit.split(1).unwrap();
+                let other = permit.split(1).unwrap();
+                drop(split);
+                other.forget();
+                // `permit` still holds `a`, so this records `a` -> `b`.
+                let _b = b1.lock();
+            }).join();
+            assert!(violations().is_empty());
+            task::spawn(move || {
+                let _b = b.lock();
+                let _a = a.acquire().unwrap();
+            }).join();
+        });
+        assert_eq!(violations().len(), 1);
+        disable();
+    }
+
+    #[test]
+    fn test_cancelled_waiter() {
+        enable();
+        let a = Rc::new(Mutex::new(()));
+        let b = Rc::new(Mutex::new(()));
+        Builder::new().build().block_on({
+            let a = a.clone();
+            move || {
+                let _a = a.lock();
+                let waiter = task::spawn({
+                    let a = a.clone();
+                    move || drop(a.lock())
+                });
+                task::yield_now();
+                waiter.cancel();
+                task::yield_now();
+            }
+        });
+        // Task ids start over, a stale `a` held by task 1 would record `a` -> `b` here.
+        Builder::new().build().block_on(move || {
+            let b1 = b.clone();
+            task::spawn(move || drop(b1.lock())).join();
+            task::spawn(move || {
+                let _b = b.lock();
+                let _a = a.lock();
+            }).join();
+        });
+        assert!(violations().is_empty());
+        disable();
+    }
+}
@@ -7,6 +7,7 @@ pub(crate) mod semaphore;
 pub(crate) mod rwlock;
 pub mod mpsc;
 pub mod oneshot;
+pub mod lockdep;
 
 pub use mutex::{
     Mutex,
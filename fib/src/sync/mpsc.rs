@@ -1,19 +1,25 @@
 use std::{cell::RefCell, collections::VecDeque, fmt::Debug, rc::Rc};
 
-use crate::{runtime::{cur_task, runtime, wake_task}, sync::Mutex, task::{packet::Packet, BlockCause}};
+use crate::{metrics::Instrument, runtime::{runtime, wake_waiter}, select::Selectable, sync::Mutex, task::BlockCause};
 
 struct Channel<T> {
+    /// What waiters block on, i.e. the address of the `RefCell` around the channel.
+    addr: usize,
     buffer: VecDeque<T>,
     receiver_waiter: Option<usize>,
     closed: bool,
+    stats: Instrument,
 }
 
 pub fn channel<T: 'static>() -> (Sender<T>, Receiver<T>) {
     let channel = Rc::new(RefCell::new(Channel {
+        addr: 0,
         buffer: VecDeque::<T>::new(),
         receiver_waiter: None,
         closed: false,
+        stats: Instrument::none(),
     }));
+    channel.borrow_mut().addr = Rc::as_ptr(&channel) as *const () as usize;
     
     let sender = Sender {
         channel: channel.clone(),
@@ -30,12 +36,15 @@ pub fn channel<T: 'static>() -> (Sender<T>, Receiver<T>) {
 /// __NOTE__ Zero capacity currently not supported.
 pub fn sync_channel<T: 'static>(capacity: usize) -> (SyncSender<T>, Receiver<T>) {
     let channel = Rc::new(RefCell::new(SyncChannel {
+        addr: 0,
         buffer: VecDeque::<T>::with_capacity(capacity),
         capacity,
         sender_waiters: VecDeque::new(),
         receiver_waiter: None,
         closed: false,
+        stats: Instrument::none(),
     }));
+    channel.borrow_mut().addr = Rc::as_ptr(&channel) as *const () as usize;
 
     let sender = SyncSender {
         inner: Sender {
@@ -52,11 +61,14 @@ pub fn sync_channel<T: 'static>(capacity: usize) -> (SyncSender<T>, Receiver<T>)
 }
 
 struct SyncChannel<T> {
+    /// See `Channel::addr`.
+    addr: usize,
     buffer: VecDeque<T>,
     capacity: usize,
     sender_waiters: VecDeque<usize>,
     receiver_waiter: Option<usize>,
     closed: bool,
+    stats: Instrument,
 }
 
 #[derive(Debug)]
@@ -87,9 +99,13 @@ trait ChannelTrait<T> {
     fn recv(&mut self) -> Result<T, TryRecvError>;
 
     fn add_recv_waiter(&mut self, id: usize);
+    fn remove_recv_waiter(&mut self, id: usize);
     fn add_sender_waiter(&mut self, id: usize);
+    /// The task `id` unwinds out of a wait, see `Runtime::block_or_abandon`.
+    fn abandon(&mut self, id: usize);
     fn close(&mut self);
     fn is_closed(&self) -> bool;
+    fn stats(&mut self) -> &mut Instrument;
 }
 
 #[derive(Clone)]
@@ -104,11 +120,13 @@ pub struct SyncSender<T> {
 }
 
 impl<T> SyncSender<T> {
+    #[track_caller]
     pub fn send(&self, item: T) -> Result<(), SendError<T>> {
         self.inner.send(item)
     }
 
     pub fn try_send(&self, item: T) -> Result<(), TrySendError<T>> {
+        runtime().record_access(Rc::as_ptr(&self.inner.channel) as *const () as usize);
         let mut channel = self.inner.channel.borrow_mut();
         channel.send(item)
     }
@@ -135,19 +153,29 @@ impl<T> Drop for Receiver<T> {
 }
 
 impl<T> Sender<T> {
+    #[track_caller]
     pub fn send(&self, mut item: T) -> Result<(), SendError<T>> {
+        runtime().sync_point(Rc::as_ptr(&self.channel) as *const () as usize);
+        let mut start = None;
         loop {
             let mut channel = self.channel.borrow_mut();
             let res = channel.send(item);
             match res {
-                Ok(()) => return Ok(()),
+                Ok(()) => {
+                    channel.stats().waited(start);
+                    return Ok(());
+                },
                 // WouldBlock
                 Err(TrySendError::Full(item_back)) => {
                     item = item_back;
+                    start = start.or_else(|| channel.stats().contended());
                     let rt = runtime();
-                    channel.add_sender_waiter(rt.cur_task());
+                    let task = rt.cur_task();
+                    channel.add_sender_waiter(task);
                     drop(channel);
-                    rt.yield_to_base(Packet::<()>::block_on(BlockCause::Channel));
+                    rt.block_or_abandon(BlockCause::Channel, Rc::as_ptr(&self.channel) as *const () as usize, || {
+                        self.channel.borrow_mut().abandon(task);
+                    });
                 },
                 Err(TrySendError::Disconnected(item_back)) => return Err(SendError::Disconnected(item_back)),
             }
@@ -156,39 +184,83 @@ impl<T> Sender<T> {
 }
 
 impl<T> Receiver<T> {
+    #[track_caller]
     pub fn recv(&self) -> Result<T, RecvError> {
+        runtime().sync_point(Rc::as_ptr(&self.channel) as *const () as usize);
+        let mut start = None;
         loop {
             let mut channel = self.channel.borrow_mut();
             let res = channel.recv();
             match res {
-                Ok(item) => return Ok(item),
+                Ok(item) => {
+                    channel.stats().waited(start);
+                    return Ok(item);
+                },
                 // WouldBlock
                 Err(TryRecvError::Empty) => {
+                    start = start.or_else(|| channel.stats().contended());
                     let rt = runtime();
-                    channel.add_recv_waiter(rt.cur_task());
+                    let task = rt.cur_task();
+                    channel.add_recv_waiter(task);
                     drop(channel);
-                    rt.yield_to_base(Packet::<()>::block_on(BlockCause::Channel));
+                    rt.block_or_abandon(BlockCause::Channel, Rc::as_ptr(&self.channel) as *const () as usize, || {
+                        self.channel.borrow_mut().abandon(task);
+                    });
                 },
                 Err(TryRecvError::Disconnected) => return Err(RecvError::Disconnected),
             }
         }
     }
 
+    /// Name the channel, so that its contention and backlog show up in `fib::metrics`.
+    pub fn named(self, name: impl Into<String>) -> Self {
+        *self.channel.borrow_mut().stats() = Instrument::named("channel", name.into());
+        self
+    }
+
     pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        runtime().record_access(Rc::as_ptr(&self.channel) as *const () as usize);
         let mut channel = self.channel.borrow_mut();
         channel.recv()
     }
 }
 
+impl<T> Selectable for &Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn try_select(&mut self) -> Option<Self::Output> {
+        runtime().record_access(Rc::as_ptr(&self.channel) as *const () as usize);
+        let mut channel = self.channel.borrow_mut();
+        channel.remove_recv_waiter(runtime().cur_task());
+        match channel.recv() {
+            Ok(item) => Some(Ok(item)),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(RecvError::Disconnected)),
+        }
+    }
+
+    fn register(&mut self) {
+        let task = runtime().cur_task();
+        let mut channel = self.channel.borrow_mut();
+        channel.remove_recv_waiter(task);
+        channel.add_recv_waiter(task);
+    }
+
+    fn deregister(&mut self) {
+        self.channel.borrow_mut().remove_recv_waiter(runtime().cur_task());
+    }
+}
+
 impl<T> ChannelTrait<T> for Channel<T> {
     fn send(&mut self, item: T) -> Result<(), TrySendError<T>> {
         if self.closed {
             return Err(TrySendError::Disconnected(item));
         }
         self.buffer.push_back(item);
+        self.stats.set_queue_len(self.buffer.len());
  
         if let Some(waiter_id) = self.receiver_waiter.take() {
-            wake_task(waiter_id);
+            wake_waiter(waiter_id, self.addr);
         }
  
         Ok(())
@@ -204,6 +276,7 @@ impl<T> ChannelTrait<T> for Channel<T> {
         }
 
         let item = self.buffer.pop_front().unwrap();
+        self.stats.set_queue_len(self.buffer.len());
 
         Ok(item)
     }
@@ -211,11 +284,15 @@ impl<T> ChannelTrait<T> for Channel<T> {
     fn is_closed(&self) -> bool {
         self.closed
     }
+
+    fn stats(&mut self) -> &mut Instrument {
+        &mut self.stats
+    }
     
     fn close(&mut self) {
         self.closed = true;
         if let Some(recv_waiter) = self.receiver_waiter.take() {
-            wake_task(recv_waiter);
+            wake_waiter(recv_waiter, self.addr);
         }
     }
 
@@ -223,11 +300,32 @@ impl<T> ChannelTrait<T> for Channel<T> {
         assert!(self.receiver_waiter.is_none());
         self.receiver_waiter = Some(id);
     }
+
+    fn remove_recv_waiter(&mut self, id: usize) {
+        if self.receiver_waiter == Some(id) {
+            self.receiver_waiter = None;
+        }
+    }
     
     fn add_sender_waiter(&mut self, id: usize) {
         // Asynchronous channel has no sender waiters.
         unimplemented!()
     }
+
+    fn abandon(&mut self, id: usize) {
+        self.remove_recv_waiter(id);
+    }
+}
+
+impl<T> SyncChannel<T> {
+    /// Hand a free slot to the first sender which is still blocked on the channel.
+    fn wake_sender(&mut self) {
+        while let Some(sender_waiter) = self.sender_waiters.pop_front() {
+            if wake_waiter(sender_waiter, self.addr) {
+                break;
+            }
+        }
+    }
 }
 
 impl<T> ChannelTrait<T> for SyncChannel<T> {
@@ -239,12 +337,11 @@ impl<T> ChannelTrait<T> for SyncChannel<T> {
             return Err(TrySendError::Full(item));
         }
         self.buffer.push_back(item);
+        self.stats.set_queue_len(self.buffer.len());
         if let Some(waiter_id) = self.receiver_waiter.take() {
-            wake_task(waiter_id);
-        }
-        if let Some(sender_waiter) = self.sender_waiters.pop_front() {
-            wake_task(sender_waiter);
+            wake_waiter(waiter_id, self.addr);
         }
+        self.wake_sender();
         Ok(())
     }
 
@@ -257,10 +354,8 @@ impl<T> ChannelTrait<T> for SyncChannel<T> {
             return Err(TryRecvError::Empty);
         }
         let item = self.buffer.pop_front().unwrap();
-
-        if let Some(sender_waiter) = self.sender_waiters.pop_front() {
-            wake_task(sender_waiter);
-        }
+        self.stats.set_queue_len(self.buffer.len());
+        self.wake_sender();
 
         Ok(item)
     }
@@ -270,22 +365,40 @@ impl<T> ChannelTrait<T> for SyncChannel<T> {
         self.receiver_waiter = Some(id);
     }
 
+    fn remove_recv_waiter(&mut self, id: usize) {
+        if self.receiver_waiter == Some(id) {
+            self.receiver_waiter = None;
+        }
+    }
+
     fn add_sender_waiter(&mut self, id: usize) {
         assert!(!self.sender_waiters.contains(&id));
         self.sender_waiters.push_back(id);
     }
 
+    fn abandon(&mut self, id: usize) {
+        self.remove_recv_waiter(id);
+        self.sender_waiters.retain(|&waiter| waiter != id);
+        if !self.closed && self.buffer.len() < self.capacity {
+            self.wake_sender();
+        }
+    }
+
     fn close(&mut self) {
         self.closed = true;
         if let Some(recv_waiter) = self.receiver_waiter.take() {
-            wake_task(recv_waiter);
+            wake_waiter(recv_waiter, self.addr);
         }
         for sender_waiter in self.sender_waiters.drain(..) {
-            wake_task(sender_waiter);
+            wake_waiter(sender_waiter, self.addr);
         }
     }
 
     fn is_closed(&self) -> bool {
         self.closed
     }
+
+    fn stats(&mut self) -> &mut Instrument {
+        &mut self.stats
+    }
 }
\ No newline at end of file
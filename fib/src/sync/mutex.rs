@@ -1,8 +1,8 @@
 //! One of the most basic synchronization primitives.
 
-use std::{collections::VecDeque, fmt::Debug, ops::{Deref, DerefMut}};
+use std::{collections::VecDeque, fmt::Debug, ops::{Deref, DerefMut}, panic::Location};
 
-use crate::{runtime::{runtime, wake_task}, task::{packet::Packet, task::TaskState, BlockCause}, utils::STCell};
+use crate::{metrics::Instrument, runtime::{runtime, wake_waiter}, sync::lockdep, task::BlockCause, utils::STCell};
 
 pub struct Mutex<T> {
     inner: STCell<MutexInner<T>>,
@@ -22,6 +22,7 @@ struct MutexInner<T> {
     data: T,
     locked: bool,
     waiters: VecDeque<usize>,
+    stats: Instrument,
 }
 
 pub struct MutexGuard<'a, T> {
@@ -34,23 +35,59 @@ impl<T> Mutex<T> {
             data: t,
             locked: false,
             waiters: VecDeque::new(),
+            stats: Instrument::none(),
         };
         Self { inner: STCell::new(inner) }
     }
 
+    /// Name the mutex, so that its contention shows up in `fib::metrics`.
+    pub fn named(self, name: impl Into<String>) -> Self {
+        self.inner.get_mut().stats = Instrument::named("mutex", name.into());
+        self
+    }
+
+    #[track_caller]
     pub fn lock(&self) -> MutexGuard<'_, T> {
+        runtime().sync_point(self as *const Self as usize);
+        lockdep::acquire(self as *const Self as usize, Location::caller(), true);
         let inner = self.inner.get_mut();
+        let start = inner.locked.then(|| inner.stats.contended()).flatten();
         while inner.locked {
-            let mut rt = runtime();
-            inner.waiters.push_back(rt.cur_task());
-            rt.yield_to_base(Packet::<()>::block_on(BlockCause::Lock));
+            let rt = runtime();
+            let task = rt.cur_task();
+            inner.waiters.push_back(task);
+            inner.stats.set_queue_len(inner.waiters.len());
+            rt.block_or_abandon(BlockCause::Lock, self as *const Self as usize, || self.abandon(task));
         }
         inner.locked = true;
+        inner.stats.waited(start);
 
         MutexGuard {
             mutex: self,
         }
     }
+
+    /// A waiter unwinds out of `lock`, see `Runtime::block_or_abandon`.
+    fn abandon(&self, task: usize) {
+        lockdep::release(self as *const Self as usize);
+        let inner = self.inner.get_mut();
+        inner.waiters.retain(|&id| id != task);
+        if !inner.locked {
+            inner.wake_one(self as *const Self as usize);
+        }
+        inner.stats.set_queue_len(inner.waiters.len());
+    }
+}
+
+impl<T> MutexInner<T> {
+    /// Hand the unlocked mutex to the first waiter which is still blocked on it.
+    fn wake_one(&mut self, primitive: usize) {
+        while let Some(waiter_id) = self.waiters.pop_front() {
+            if wake_waiter(waiter_id, primitive) {
+                break;
+            }
+        }
+    }
 }
 
 impl<T> Deref for MutexGuard<'_, T> {
@@ -69,10 +106,11 @@ impl<T> DerefMut for MutexGuard<'_, T> {
 
 impl<T> Drop for MutexGuard<'_, T> {
     fn drop(&mut self) {
+        lockdep::release(self.mutex as *const Mutex<T> as usize);
+        runtime().record_access(self.mutex as *const Mutex<T> as usize);
         let inner = self.mutex.inner.get_mut();
         inner.locked = false;
-        if let Some(waiter_id) = inner.waiters.pop_front() {
-            wake_task(waiter_id);
-        }
+        inner.wake_one(self.mutex as *const Mutex<T> as usize);
+        inner.stats.set_queue_len(inner.waiters.len());
     }
 }
\ No newline at end of file
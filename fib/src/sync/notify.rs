@@ -1,10 +1,27 @@
-use std::{cell::RefCell, collections::{VecDeque}, rc::Rc};
+use std::{cell::RefCell, collections::{HashSet, VecDeque}, rc::Rc};
 
-use crate::{runtime::{runtime, wake_task}, sync::notify, task::packet::Packet};
+use crate::{runtime::runtime, select::Selectable, sync::notify, task::BlockCause};
 
 struct NotifyCore {
     waiters: VecDeque<usize>,
     permit: Option<()>,
+    /// Waiters which have been notified, but have not run since.
+    delivered: HashSet<usize>,
+}
+
+impl NotifyCore {
+    /// Notify the first waiter which is still blocked here, skipping stale ones,
+    /// e.g. a selecting task which has already been woken up by another branch.
+    fn wake_one(&mut self, primitive: usize, back: bool) -> bool {
+        let rt = runtime();
+        while let Some(id) = if back { self.waiters.pop_back() } else { self.waiters.pop_front() } {
+            if rt.wake_waiter(id, primitive) {
+                self.delivered.insert(id);
+                return true;
+            }
+        }
+        false
+    }
 }
 
 #[derive(Clone)]
@@ -12,49 +29,89 @@ pub struct Notify {
     core: Rc<RefCell<NotifyCore>>,
 }
 
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Notify {
     pub fn new() -> Self {
         Self {
             core: Rc::new(RefCell::new(NotifyCore {
                 waiters: VecDeque::new(),
                 permit: None,
+                delivered: HashSet::new(),
             })),
         }
     }
 
+    #[track_caller]
     pub fn wait(&self) {
+        runtime().sync_point(Rc::as_ptr(&self.core) as usize);
         let mut core = self.core.borrow_mut();
         if let Some(()) = core.permit.take() {
             return;
         }
         let rt = runtime();
-        core.waiters.push_back(rt.cur_task());
+        let task = rt.cur_task();
+        core.waiters.push_back(task);
         drop(core);
-        rt.yield_to_base(Packet::<()>::block_on(crate::task::BlockCause::Notify));
+        rt.block_or_abandon(BlockCause::Notify, Rc::as_ptr(&self.core) as usize, || self.abandon(task));
+        self.core.borrow_mut().delivered.remove(&task);
+    }
+
+    /// Stop waiting, passing on a notification delivered to `task` but not consumed.
+    fn abandon(&self, task: usize) {
+        let mut core = self.core.borrow_mut();
+        core.waiters.retain(|&id| id != task);
+        if core.delivered.remove(&task) {
+            drop(core);
+            self.notify_one();
+        }
     }
 
     pub fn notify_one(&self) {
+        runtime().record_access(Rc::as_ptr(&self.core) as usize);
         let mut core = self.core.borrow_mut();
-        if let Some(id) = core.waiters.pop_front() {
-            wake_task(id);
-        } else {
+        if !core.wake_one(Rc::as_ptr(&self.core) as usize, false) {
             core.permit = Some(());
         }
     }
 
     pub fn notify_last(&self) {
+        runtime().record_access(Rc::as_ptr(&self.core) as usize);
         let mut core = self.core.borrow_mut();
-        if let Some(id) = core.waiters.pop_back() {
-            wake_task(id);
-        } else {
+        if !core.wake_one(Rc::as_ptr(&self.core) as usize, true) {
             core.permit = Some(());
         }
     }
 
     pub fn notify_waiters(&self) {
+        runtime().record_access(Rc::as_ptr(&self.core) as usize);
+        let mut core = self.core.borrow_mut();
+        while core.wake_one(Rc::as_ptr(&self.core) as usize, false) {}
+    }
+}
+
+impl Selectable for &Notify {
+    type Output = ();
+
+    fn try_select(&mut self) -> Option<()> {
+        runtime().record_access(Rc::as_ptr(&self.core) as usize);
         let mut core = self.core.borrow_mut();
-        while let Some(id) = core.waiters.pop_front() {
-            wake_task(id);
+        (core.permit.take().is_some() || core.delivered.remove(&runtime().cur_task())).then_some(())
+    }
+
+    fn register(&mut self) {
+        let mut core = self.core.borrow_mut();
+        let task = runtime().cur_task();
+        if !core.waiters.contains(&task) {
+            core.waiters.push_back(task);
         }
     }
+
+    fn deregister(&mut self) {
+        self.abandon(runtime().cur_task());
+    }
 }
\ No newline at end of file
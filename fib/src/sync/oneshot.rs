@@ -1,6 +1,6 @@
 use std::{cell::{OnceCell, RefCell}, rc::Rc};
 
-use crate::{runtime::{runtime, wake_task}, task::{packet::Packet, BlockCause}};
+use crate::{runtime::{runtime, wake_waiter}, select::Selectable, task::BlockCause};
 
 pub fn channel<T: 'static>() -> (Sender<T>, Receiver<T>) {
     let channel = Rc::new(RefCell::new(Channel {
@@ -46,15 +46,14 @@ pub enum TryRecvError {
 
 impl<T> Sender<T> {
     pub fn send(self, item: T) -> Result<(), T> {
+        runtime().record_access(Rc::as_ptr(&self.channel) as usize);
         let mut channel = self.channel.borrow_mut();
         if channel.closed {
             return Err(item);
         }
-        if let Err(item) = channel.item.set(item) {
-            return Err(item);
-        }
+        channel.item.set(item)?;
         if let Some(receiver) = channel.receiver_waiter.take() {
-            wake_task(receiver);
+            wake_waiter(receiver, Rc::as_ptr(&self.channel) as usize);
         }
         Ok(())
     }
@@ -69,7 +68,7 @@ impl<T> Drop for Sender<T> {
         let mut channel = self.channel.borrow_mut();
         channel.closed = true;
         if let Some(receiver) = channel.receiver_waiter.take() {
-            wake_task(receiver);
+            wake_waiter(receiver, Rc::as_ptr(&self.channel) as usize);
         }
     }
 }
@@ -80,6 +79,7 @@ impl<T> Receiver<T> {
     }
 
     pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        runtime().record_access(Rc::as_ptr(&self.channel) as usize);
         let mut channel = self.channel.borrow_mut();
         
         if let Some(item) = channel.item.take() {
@@ -93,7 +93,9 @@ impl<T> Receiver<T> {
         }
     }
 
+    #[track_caller]
     pub fn blocking_recv(self) -> Result<T, RecvError> {
+        runtime().sync_point(Rc::as_ptr(&self.channel) as usize);
         let mut channel = self.channel.borrow_mut();
 
         if let Some(item) = channel.item.take() {
@@ -106,7 +108,7 @@ impl<T> Receiver<T> {
         let rt = runtime();
         channel.receiver_waiter = Some(rt.cur_task());
         drop(channel);
-        rt.yield_to_base(Packet::<()>::block_on(BlockCause::Channel));
+        rt.block(BlockCause::Channel, Rc::as_ptr(&self.channel) as usize);
         channel = self.channel.borrow_mut();
 
         if let Some(item) = channel.item.take() {
@@ -117,6 +119,33 @@ impl<T> Receiver<T> {
     }
 }
 
+impl<T> Selectable for &mut Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn try_select(&mut self) -> Option<Self::Output> {
+        runtime().record_access(Rc::as_ptr(&self.channel) as usize);
+        let mut channel = self.channel.borrow_mut();
+        if let Some(item) = channel.item.take() {
+            Some(Ok(item))
+        } else if channel.closed {
+            Some(Err(RecvError::Closed))
+        } else {
+            None
+        }
+    }
+
+    fn register(&mut self) {
+        self.channel.borrow_mut().receiver_waiter = Some(runtime().cur_task());
+    }
+
+    fn deregister(&mut self) {
+        let mut channel = self.channel.borrow_mut();
+        if channel.receiver_waiter == Some(runtime().cur_task()) {
+            channel.receiver_waiter = None;
+        }
+    }
+}
+
 impl<T> Drop for Receiver<T> {
     fn drop(&mut self) {
         self.channel.borrow_mut().closed = true;
@@ -1,6 +1,6 @@
-use std::{cell::RefCell, collections::VecDeque, ops::{Deref, DerefMut}};
+use std::{cell::RefCell, collections::VecDeque, ops::{Deref, DerefMut}, panic::Location};
 
-use crate::{runtime::{runtime, wake_task}, task::packet::Packet, utils::STCell};
+use crate::{runtime::{runtime, wake_waiter}, sync::lockdep, task::BlockCause, utils::STCell};
 
 
 pub struct RwLock<T> {
@@ -41,12 +41,16 @@ impl<T> RwLock<T> {
         }
     }
 
+    #[track_caller]
     pub fn read(&self)  -> RwLockReadGuard<'_, T> {
+        runtime().sync_point(self as *const Self as usize);
+        lockdep::acquire(self as *const Self as usize, Location::caller(), true);
         let mut inner = self.inner.get_mut();
         while inner.state == RwLockState::Write {
             let rt = runtime();
-            inner.waiters.push_back((rt.cur_task(), Access::Read));
-            rt.yield_to_base(Packet::<()>::block_on(crate::task::BlockCause::Lock));
+            let task = rt.cur_task();
+            inner.waiters.push_back((task, Access::Read));
+            rt.block_or_abandon(BlockCause::Lock, self as *const Self as usize, || self.abandon(task));
         }
         inner.state = RwLockState::Read;
         inner.reader_count += 1;
@@ -54,12 +58,16 @@ impl<T> RwLock<T> {
         RwLockReadGuard { rwlock: self }
     }
 
+    #[track_caller]
     pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        runtime().sync_point(self as *const Self as usize);
+        lockdep::acquire(self as *const Self as usize, Location::caller(), true);
         let mut inner = self.inner.get_mut();
         while inner.state != RwLockState::None {
             let rt = runtime();
-            inner.waiters.push_back((rt.cur_task(), Access::Write));
-            rt.yield_to_base(Packet::<()>::block_on(crate::task::BlockCause::Lock));
+            let task = rt.cur_task();
+            inner.waiters.push_back((task, Access::Write));
+            rt.block_or_abandon(BlockCause::Lock, self as *const Self as usize, || self.abandon(task));
         }
         assert!(inner.reader_count == 0);
         inner.state = RwLockState::Write;
@@ -67,32 +75,32 @@ impl<T> RwLock<T> {
         RwLockWriteGuard { rwlock: self }
     }
 
+    /// A waiter unwinds out of `read` or `write`, see `Runtime::block_or_abandon`.
+    fn abandon(&self, task: usize) {
+        lockdep::release(self as *const Self as usize);
+        let inner = self.inner.get_mut();
+        inner.waiters.retain(|&(id, _)| id != task);
+        if inner.state == RwLockState::None {
+            inner.wake_up(self as *const Self as usize);
+        }
+    }
 }
 
 impl<T> RwLockInner<T> {
-    fn wake_up(&mut self) {
+    /// Wake the next writer, or every reader up to the next writer.
+    /// Stale waiters are skipped, see `Runtime::wake_waiter`.
+    fn wake_up(&mut self, primitive: usize) {
         assert!(self.state == RwLockState::None);
-        let mut next_access;
-        if let Some((_, access)) = self.waiters.front() {
-            next_access = *access;
-        } else {
-            return;
-        }
-        match next_access {
-            Access::Write => {
-                // self.state = RwLockState::Write;
-                let (task_id, _) = self.waiters.pop_front().unwrap();
-                wake_task(task_id);
-            },
-            Access::Read => {
-                // self.state = RwLockState::Read;
-                while let Some((task_id, access)) = self.waiters.pop_front() {
-                    if access == Access::Read {
-                        wake_task(task_id);
-                    } else {
-                        self.waiters.push_front((task_id, access));
-                        break;
-                    }
+        let mut woken_reader = false;
+        while let Some(&(task_id, access)) = self.waiters.front() {
+            if access == Access::Write && woken_reader {
+                break;
+            }
+            self.waiters.pop_front();
+            if wake_waiter(task_id, primitive) {
+                match access {
+                    Access::Write => break,
+                    Access::Read => woken_reader = true,
                 }
             }
         }
@@ -113,11 +121,13 @@ impl<'a, T> Deref for RwLockReadGuard<'a, T> {
 
 impl<'a, T> Drop for RwLockReadGuard<'a, T> {
     fn drop(&mut self) {
+        lockdep::release(self.rwlock as *const RwLock<T> as usize);
+        runtime().record_access(self.rwlock as *const RwLock<T> as usize);
         let inner = self.rwlock.inner.get_mut();
         inner.reader_count -= 1;
         if inner.reader_count == 0 {
             inner.state = RwLockState::None;
-            inner.wake_up();   
+            inner.wake_up(self.rwlock as *const RwLock<T> as usize);
         }
     }
 }
@@ -128,9 +138,11 @@ pub struct RwLockWriteGuard<'a, T> {
 
 impl<'a, T> Drop for RwLockWriteGuard<'a, T> {
     fn drop(&mut self) {
+        lockdep::release(self.rwlock as *const RwLock<T> as usize);
+        runtime().record_access(self.rwlock as *const RwLock<T> as usize);
         let inner = self.rwlock.inner.get_mut();
         inner.state = RwLockState::None;
-        inner.wake_up();
+        inner.wake_up(self.rwlock as *const RwLock<T> as usize);
     }
 }
 
@@ -1,12 +1,13 @@
-use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+use std::{cell::RefCell, collections::VecDeque, panic::Location, rc::Rc};
 
-use crate::{runtime::{runtime, wake_task}, task::packet::Packet};
+use crate::{metrics::Instrument, runtime::{runtime, wake_waiter}, sync::lockdep, task::BlockCause};
 
 
 struct SemaphoreCore {
     permits: usize,
     waiters: VecDeque<usize>,
     closed: bool,
+    stats: Instrument,
 }
 
 pub struct Semaphore {
@@ -37,10 +38,17 @@ impl Semaphore {
                 permits,
                 waiters: VecDeque::new(),
                 closed: false,
+                stats: Instrument::none(),
             })),
         }
     }
 
+    /// Name the semaphore, so that its contention shows up in `fib::metrics`.
+    pub fn named(self, name: impl Into<String>) -> Self {
+        self.core.borrow_mut().stats = Instrument::named("semaphore", name.into());
+        self
+    }
+
     pub fn available_permits(&self) -> usize {
         self.core.borrow().permits
     }
@@ -53,13 +61,10 @@ impl Semaphore {
             return;
         }
         
+        runtime().record_access(self.addr());
         let mut core = self.core.borrow_mut();
         core.permits += permits;
-        while core.permits > 0 && !core.waiters.is_empty() {
-            let waiter = core.waiters.pop_front().unwrap();
-            wake_task(waiter);
-            core.permits -= 1;
-        }
+        core.wake_up(self.addr());
     }
 
     /// Forget the specified number of permits, returning the number of permits that were actually forgotten.
@@ -75,46 +80,71 @@ impl Semaphore {
         actual_forgotten
     }
 
+    #[track_caller]
     pub fn acquire(&self) -> Result<SemaphorePermit<'_>, AcquireError> {
+        runtime().sync_point(self.addr());
         let mut core = self.core.borrow_mut();
         if core.closed {
             return Err(AcquireError);
         }
-        if core.permits > 0 {
-            core.permits -= 1;
-            return Ok(SemaphorePermit { sem: self, permits: 1 })
-        }
-        let rt = runtime();
-        core.waiters.push_back(rt.cur_task());
-        drop(core);
-        rt.yield_to_base(Packet::<()>::block_on(crate::task::BlockCause::Semaphore));
-
-        core = self.core.borrow_mut();
-        if core.closed {
-            return Err(AcquireError);
+        lockdep::acquire(self.addr(), Location::caller(), true);
+        let start = (core.permits == 0).then(|| core.stats.contended()).flatten();
+        while core.permits == 0 {
+            let rt = runtime();
+            let task = rt.cur_task();
+            core.waiters.push_back(task);
+            core.stats.set_queue_len(core.waiters.len());
+            drop(core);
+            rt.block_or_abandon(BlockCause::Semaphore, self.addr(), || self.abandon(task));
+
+            core = self.core.borrow_mut();
+            if core.closed {
+                core.stats.waited(start);
+                lockdep::release(self.addr());
+                return Err(AcquireError);
+            }
         }
-        assert!(core.permits > 0, "Semaphore permits should be available after yielding");
+        core.stats.waited(start);
         core.permits -= 1;
         Ok(SemaphorePermit { sem: self, permits: 1 })
     }
 
+    #[track_caller]
     pub fn try_acquire(&self) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        runtime().record_access(self.addr());
         let mut core = self.core.borrow_mut();
         if core.closed {
             return Err(TryAcquireError::Closed);
         }
         if core.permits > 0 {
             core.permits -= 1;
+            lockdep::acquire(self.addr(), Location::caller(), false);
             return Ok(SemaphorePermit { sem: self, permits: 1 });
         }
         Err(TryAcquireError::NoPermits)
     }
 
+    /// A waiter unwinds out of `acquire`, see `Runtime::block_or_abandon`.
+    fn abandon(&self, task: usize) {
+        lockdep::release(self.addr());
+        let mut core = self.core.borrow_mut();
+        core.waiters.retain(|&id| id != task);
+        if !core.closed {
+            core.wake_up(self.addr());
+        }
+        core.stats.set_queue_len(core.waiters.len());
+    }
+
+    fn addr(&self) -> usize {
+        Rc::as_ptr(&self.core) as usize
+    }
+
     pub fn is_closed(&self) -> bool {
         self.core.borrow().closed
     }
 
     pub fn close(&self) {
+        runtime().record_access(self.addr());
         let mut core = self.core.borrow_mut();
         if core.closed {
             return;
@@ -122,8 +152,23 @@ impl Semaphore {
         core.closed = true;
 
         while let Some(waiter) = core.waiters.pop_front() {
-            wake_task(waiter);
+            wake_waiter(waiter, self.addr());
+        }
+        core.stats.set_queue_len(0);
+    }
+}
+
+impl SemaphoreCore {
+    /// Wake as many waiters as there are permits.
+    /// Woken waiters take their permit once they run, and queue up again if it is gone.
+    fn wake_up(&mut self, primitive: usize) {
+        let mut woken = 0;
+        while woken < self.permits && let Some(waiter) = self.waiters.pop_front() {
+            if wake_waiter(waiter, primitive) {
+                woken += 1;
+            }
         }
+        self.stats.set_queue_len(self.waiters.len());
     }
 }
 
@@ -132,6 +177,8 @@ impl SemaphorePermit<'_> {
         self.permits
     }
 
+    /// Drop the permit without returning its permits to the semaphore.
+    /// The semaphore no longer counts as held through this permit.
     pub fn forget(mut self) {
         self.permits = 0;
     }
@@ -152,16 +199,18 @@ impl SemaphorePermit<'_> {
         }
 
         self.permits -= n;
+        lockdep::share(self.sem.addr());
 
         Some(Self {
             sem: self.sem,
-            permits: n as usize,
+            permits: n,
         })
     }
 }
 
 impl Drop for SemaphorePermit<'_> {
     fn drop(&mut self) {
+        lockdep::release(self.sem.addr());
         self.sem.add_permits(self.permits);
     }
 }
@@ -0,0 +1,129 @@
+//! Queries about tasks, for diagnostics and admin endpoints.
+
+use std::{cell::Cell, fmt, rc::Rc, time::{Duration, Instant}};
+
+use crate::{runtime::{runtime, Runtime}, task::task::TaskState};
+
+/// Identifies a task for the lifetime of its runtime. Ids are never reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(pub(crate) usize);
+
+impl TaskId {
+    /// The raw id, e.g. for logging or as a key in external tables.
+    pub fn as_usize(self) -> usize {
+        self.0
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A handle for inspecting a task, see `task::current` and `Runtime::tasks`.
+/// The state follows the task, wherever the handle is queried from.
+#[derive(Debug, Clone)]
+pub struct TaskRef {
+    id: TaskId,
+    name: Option<String>,
+    spawned_at: Instant,
+    cpu_time: Rc<Cell<Duration>>,
+    state: Rc<Cell<TaskState>>,
+}
+
+impl TaskRef {
+    pub(crate) fn new(rt: &Runtime, id: usize) -> Self {
+        let info = &rt.tasks[&id];
+        Self {
+            id: TaskId(id),
+            name: info.name.clone(),
+            spawned_at: info.spawned_at,
+            cpu_time: info.cpu_time.clone(),
+            state: info.state.clone(),
+        }
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn state(&self) -> TaskState {
+        self.state.get()
+    }
+
+    /// When the task was spawned, on the runtime's clock (see `fib::time::now`).
+    pub fn spawned_at(&self) -> Instant {
+        self.spawned_at
+    }
+
+    /// Thread CPU time the task has spent running, up to its last suspension.
+    /// Keeps counting, and stays available once the task has finished.
+    /// Zero unless the runtime tracks CPU time, see `Runtime::track_cpu_time`.
+    pub fn cpu_time(&self) -> Duration {
+        self.cpu_time.get()
+    }
+}
+
+/// The task which is currently running.
+/// Panics outside of a task.
+#[track_caller]
+pub fn current() -> TaskRef {
+    let rt = runtime();
+    let cur = rt.cur_task();
+    assert!(rt.tasks.contains_key(&cur), "task::current called outside of a task");
+    TaskRef::new(rt, cur)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, time::Duration};
+
+    use crate::{runtime::{runtime, Builder}, sync::Notify, task::{self, BlockCause, TaskState}, time};
+
+    #[fib::test(paused_time)]
+    fn test_introspection() {
+        let start = time::now();
+        let me = task::current();
+        assert_eq!(me.state(), TaskState::Running);
+        assert_eq!(me.spawned_at(), start);
+
+        time::advance(Duration::from_secs(1));
+        let notify = Rc::new(Notify::new());
+        let waiter_notify = notify.clone();
+        let child = task::Builder::new().name("child".to_string()).spawn(|| task::current().id());
+        let waiter = task::spawn(move || waiter_notify.wait());
+        task::yield_now();
+
+        let tasks: Vec<_> = runtime().tasks().collect();
+        assert_eq!(tasks.len(), 2);
+        assert_eq!(tasks[0].id(), me.id());
+        assert_eq!(tasks[1].id(), waiter.id());
+        assert_eq!(tasks[1].state(), TaskState::Blocked(BlockCause::Notify));
+        assert_eq!(tasks[1].spawned_at(), start + Duration::from_secs(1));
+
+        assert_eq!(child.id(), child.join());
+        notify.notify_one();
+        waiter.join();
+        assert_eq!(tasks[1].state(), TaskState::Finished);
+    }
+
+    #[test]
+    fn test_state_outside_runtime() {
+        let mut rt = Builder::new().build();
+        let tasks: Vec<_> = rt.block_on(|| {
+            let notify = Rc::new(Notify::new());
+            task::spawn(move || notify.wait());
+            task::yield_now();
+            runtime().tasks().collect()
+        });
+        // Queried from the default runtime of the thread, not the one owning the task.
+        assert_eq!(tasks[1].state(), TaskState::Blocked(BlockCause::Notify));
+        drop(rt);
+        assert_eq!(tasks[1].state(), TaskState::Finished);
+    }
+}
@@ -0,0 +1,178 @@
+//! `join!` and `try_join!`, running several expressions as concurrent tasks of a scope.
+
+use crate::task::scope::ScopedJoinHandle;
+
+/// Run the expressions as concurrent tasks and wait for all of them, returning their results
+/// as a tuple. The expressions may borrow from the enclosing function, see `task::scope`.
+///
+/// ```ignore
+/// let (a, b) = fib::join!(fetch(&left), fetch(&right));
+/// ```
+#[macro_export]
+macro_rules! join {
+    ($($e:expr),+ $(,)?) => {
+        $crate::task::scope(|__fib_scope| {
+            $crate::task::__JoinAll::join_all(($( __fib_scope.spawn(|| $e), )+))
+        })
+    };
+}
+
+/// Like `join!` for expressions returning `Result`s with the same error type.
+/// The first `Err` cancels the tasks which are still running and is returned.
+#[macro_export]
+macro_rules! try_join {
+    ($($e:expr),+ $(,)?) => {
+        $crate::task::scope(|__fib_scope| {
+            $crate::task::__TryJoinAll::try_join_all(($(
+                __fib_scope.spawn(|| {
+                    let __result = $e;
+                    if __result.is_err() {
+                        __fib_scope.cancel_all();
+                    }
+                    __result
+                }),
+            )+))
+        })
+    };
+}
+
+#[doc(hidden)]
+pub trait __JoinAll {
+    type Output;
+
+    fn join_all(self) -> Self::Output;
+}
+
+#[doc(hidden)]
+pub trait __TryJoinAll {
+    type Output;
+
+    fn try_join_all(self) -> Self::Output;
+}
+
+macro_rules! impl_join_all {
+    ($($T:ident $h:ident),+) => {
+        impl<$($T),+> __JoinAll for ($(ScopedJoinHandle<'_, $T>,)+) {
+            type Output = ($($T,)+);
+
+            #[track_caller]
+            fn join_all(self) -> Self::Output {
+                let ($($h,)+) = self;
+                ($($h.join(),)+)
+            }
+        }
+
+        impl<E, $($T),+> __TryJoinAll for ($(ScopedJoinHandle<'_, Result<$T, E>>,)+) {
+            type Output = Result<($($T,)+), E>;
+
+            #[track_caller]
+            fn try_join_all(self) -> Self::Output {
+                let ($($h,)+) = self;
+                // Tasks cancelled because of an error have no result.
+                let mut error = None;
+                $(
+                    let $h = match $h.join_opt() {
+                        Some(Ok(value)) => Some(value),
+                        Some(Err(err)) => {
+                            error.get_or_insert(err);
+                            None
+                        },
+                        None => None,
+                    };
+                )+
+                if let Some(err) = error {
+                    return Err(err);
+                }
+                Ok(($($h.expect("Task was cancelled"),)+))
+            }
+        }
+    };
+}
+
+impl_join_all!(A a);
+impl_join_all!(A a, B b);
+impl_join_all!(A a, B b, C c);
+impl_join_all!(A a, B b, C c, D d);
+impl_join_all!(A a, B b, C c, D d, E2 e);
+impl_join_all!(A a, B b, C c, D d, E2 e, F f);
+impl_join_all!(A a, B b, C c, D d, E2 e, F f, G g);
+impl_join_all!(A a, B b, C c, D d, E2 e, F f, G g, H h);
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use crate::{sync::{Mutex, Notify}, task};
+
+    struct DropFlag<'a>(&'a Cell<bool>);
+
+    impl Drop for DropFlag<'_> {
+        fn drop(&mut self) {
+            self.0.set(true);
+        }
+    }
+
+    #[fib::test]
+    fn test_join_borrows() {
+        let mut log = vec![];
+        let log_ref = &std::cell::RefCell::new(&mut log);
+        let (a, b, c) = fib::join!(
+            { log_ref.borrow_mut().push(1); task::yield_now(); 1 },
+            { log_ref.borrow_mut().push(2); "two" },
+            log_ref.borrow().len(),
+        );
+        assert_eq!((a, b, c), (1, "two", 2));
+        assert_eq!(log, [1, 2]);
+    }
+
+    #[fib::test]
+    fn test_try_join_cancels() {
+        let never = Notify::new();
+        let dropped = Cell::new(false);
+        let result: Result<(i32, i32), &str> = fib::try_join!(
+            {
+                let _flag = DropFlag(&dropped);
+                never.wait();
+                Ok(1)
+            },
+            {
+                task::yield_now();
+                Err("failed")
+            },
+        );
+        assert_eq!(result, Err("failed"));
+        assert!(dropped.get());
+
+        let result: Result<_, ()> = fib::try_join!(Ok(1), Ok(2));
+        assert_eq!(result, Ok((1, 2)));
+    }
+
+    #[fib::test]
+    #[should_panic(expected = "Task was cancelled")]
+    fn test_cancel() {
+        let handle = task::spawn(|| loop {
+            task::yield_now();
+        });
+        task::yield_now();
+        handle.cancel();
+        handle.join();
+    }
+
+    #[fib::test(timeout = "5s")]
+    fn test_cancel_scoped_lock_waiter() {
+        let mutex = Mutex::new(0);
+        task::scope(|s| {
+            let guard = mutex.lock();
+            let cancelled = s.spawn(|| *mutex.lock() += 1);
+            let waiter = s.spawn(|| *mutex.lock() += 10);
+            task::yield_now();
+            cancelled.cancel();
+            task::yield_now();
+            // The cancelled task is still queued on the mutex, the unlock must skip it.
+            drop(guard);
+            waiter.join();
+            assert!(cancelled.is_finished());
+        });
+        assert_eq!(*mutex.lock(), 10);
+    }
+}
@@ -0,0 +1,201 @@
+//! Fiber-local storage, see `task_local!`.
+
+use std::{any::Any, collections::HashMap, fmt, marker::PhantomData, rc::Rc};
+
+use crate::runtime::runtime;
+
+/// Values of the task locals set in a task, keyed by the address of their `LocalKey`.
+pub(crate) type TaskLocals = HashMap<usize, Rc<dyn Any>>;
+
+/// Declare fiber-local values. Unlike `thread_local!` values, which are shared by
+/// every fiber on the thread, each task has a value of its own, set with `set` or `scope`.
+/// Tasks spawned with `task::Builder::inherit_locals` and the tasks of a `task::scope`
+/// start out with the values of the spawning task.
+///
+/// ```ignore
+/// fib::task_local! {
+///     static TRACE_ID: u64;
+/// }
+///
+/// TRACE_ID.scope(42, || handle_request());
+/// ```
+#[macro_export]
+macro_rules! task_local {
+    () => {};
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty $(; $($rest:tt)*)?) => {
+        $(#[$attr])*
+        $vis static $name: $crate::task::LocalKey<$t> = $crate::task::LocalKey::__new(stringify!($name));
+        $($crate::task_local!($($rest)*);)?
+    };
+}
+
+/// A key declared by `task_local!`.
+pub struct LocalKey<T: 'static> {
+    // Also keeps the key from being zero-sized, so that every key has an address of its own.
+    name: &'static str,
+    _marker: PhantomData<fn() -> T>,
+}
+
+/// Returned by `LocalKey::try_with` if the current task has no value for the key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessError {
+    name: &'static str,
+}
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "task local `{}` is not set", self.name)
+    }
+}
+
+impl std::error::Error for AccessError {}
+
+impl<T: 'static> LocalKey<T> {
+    #[doc(hidden)]
+    pub const fn __new(name: &'static str) -> Self {
+        Self { name, _marker: PhantomData }
+    }
+
+    fn key(&'static self) -> usize {
+        self as *const Self as usize
+    }
+
+    #[track_caller]
+    fn locals(&'static self) -> &'static mut TaskLocals {
+        let rt = runtime();
+        let cur = rt.cur_task();
+        match rt.tasks.get_mut(&cur) {
+            Some(info) => &mut info.locals,
+            None => panic!("task local `{}` accessed outside of a task", self.name),
+        }
+    }
+
+    /// Set the value for the rest of the current task.
+    /// Panics outside of a task.
+    #[track_caller]
+    pub fn set(&'static self, value: T) {
+        self.locals().insert(self.key(), Rc::new(value));
+    }
+
+    /// Run `f` with the value set, restoring the previous value afterwards, even if `f` unwinds.
+    /// Panics outside of a task.
+    #[track_caller]
+    pub fn scope<F, R>(&'static self, value: T, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        struct Restore {
+            key: usize,
+            task: usize,
+            prev: Option<Rc<dyn Any>>,
+        }
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                // The task's locals are gone if it is being torn down.
+                let Some(info) = runtime().tasks.get_mut(&self.task) else {
+                    return;
+                };
+                match self.prev.take() {
+                    Some(prev) => info.locals.insert(self.key, prev),
+                    None => info.locals.remove(&self.key),
+                };
+            }
+        }
+
+        let prev = self.locals().insert(self.key(), Rc::new(value));
+        let _restore = Restore { key: self.key(), task: runtime().cur_task(), prev };
+        f()
+    }
+
+    /// Run `f` with a reference to the value of the current task.
+    /// Panics if the value is not set.
+    #[track_caller]
+    pub fn with<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        match self.try_with(f) {
+            Ok(result) => result,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Like `with`, but returns an error instead of panicking if the value is not set,
+    /// or if there is no current task.
+    pub fn try_with<F, R>(&'static self, f: F) -> Result<R, AccessError>
+    where
+        F: FnOnce(&T) -> R,
+    {
+        let rt = runtime();
+        // Hold a reference of our own, `f` may yield and let other tasks replace the value.
+        let value = rt.tasks.get(&rt.cur_task())
+            .and_then(|info| info.locals.get(&self.key()))
+            .cloned()
+            .ok_or(AccessError { name: self.name })?;
+        Ok(f(value.downcast_ref().unwrap()))
+    }
+
+    /// A copy of the value of the current task.
+    /// Panics if the value is not set.
+    #[track_caller]
+    pub fn get(&'static self) -> T
+    where
+        T: Clone,
+    {
+        self.with(T::clone)
+    }
+}
+
+/// The locals of the current task, for a task spawned from it to inherit.
+pub(crate) fn inherited() -> TaskLocals {
+    let rt = runtime();
+    rt.tasks.get(&rt.cur_task())
+        .map(|info| info.locals.clone())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::task;
+
+    fib::task_local! {
+        static TRACE_ID: u64;
+        static USER: String;
+    }
+
+    #[fib::test]
+    fn test_scope() {
+        assert!(TRACE_ID.try_with(|_| ()).is_err());
+        TRACE_ID.scope(1, || {
+            let other = task::spawn(|| {
+                TRACE_ID.scope(2, || {
+                    task::yield_now();
+                    TRACE_ID.get()
+                })
+            });
+            task::yield_now();
+            assert_eq!(TRACE_ID.get(), 1);
+            assert_eq!(other.join(), 2);
+        });
+        assert_eq!(TRACE_ID.try_with(|_| ()).unwrap_err().to_string(), "task local `TRACE_ID` is not set");
+    }
+
+    #[fib::test]
+    fn test_inherit() {
+        TRACE_ID.set(7);
+        USER.set("alice".to_string());
+        let inherited = task::Builder::new().inherit_locals().spawn(|| {
+            USER.set("bob".to_string());
+            (TRACE_ID.get(), USER.get())
+        });
+        let fresh = task::spawn(|| TRACE_ID.try_with(|id| *id).is_err());
+        assert_eq!(inherited.join(), (7, "bob".to_string()));
+        assert!(fresh.join());
+        // The child's `set` does not leak back.
+        assert_eq!(USER.get(), "alice");
+
+        let (id,) = fib::join!(TRACE_ID.get());
+        assert_eq!(id, 7);
+    }
+}
@@ -1,28 +1,71 @@
 //! Task management module
 
-use crate::{runtime::runtime, task::{packet::Packet, task::JoinHandle}};
+use crate::{runtime::runtime, task::packet::Packet};
 
+#[allow(clippy::module_inception)]
 pub(crate) mod task;
 pub(crate) mod packet;
+pub(crate) mod stack;
+pub(crate) mod no_yield;
+pub(crate) mod scope;
+pub(crate) mod join;
+pub(crate) mod local;
+pub(crate) mod introspect;
 
+pub use no_yield::{no_yield, NoYieldGuard};
+pub use scope::{scope, Scope, ScopedJoinHandle};
+pub use task::{JoinHandle, TaskState};
+pub use introspect::{current, TaskId, TaskRef};
+pub use local::{AccessError, LocalKey};
+#[doc(hidden)]
+pub use join::{__JoinAll, __TryJoinAll};
+
+/// What a blocked task is waiting for.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub(crate) enum BlockCause {
+pub enum BlockCause {
     Lock,
     Channel,
     Notify,
     Barrier,
     Semaphore,
+    Join,
+    Timer,
+    Select,
+}
+
+impl BlockCause {
+    pub const ALL: [BlockCause; 8] = [
+        BlockCause::Lock,
+        BlockCause::Channel,
+        BlockCause::Notify,
+        BlockCause::Barrier,
+        BlockCause::Semaphore,
+        BlockCause::Join,
+        BlockCause::Timer,
+        BlockCause::Select,
+    ];
 }
 
+/// Unwinding payload used to tear down cancelled tasks.
+pub(crate) struct Cancelled;
+
+#[track_caller]
 pub fn yield_now() {
     let rt = runtime();
+    rt.record_access_all();
     rt.yield_to_base(Packet::<()>::_yield());
 }
 
-pub fn wait(id: usize) {
+/// Block the current task until the task with the given id has finished.
+#[track_caller]
+pub fn wait(id: TaskId) {
+    let id = id.0;
+    runtime().sync_point(id);
     let mut rt = runtime();
     while rt.cxs.contains_key(&id) {
-        yield_now();
+        let cur = rt.cur_task();
+        rt.joiners.entry(id).or_default().push(cur);
+        rt.block(BlockCause::Join, id);
     }
 }
 
@@ -31,6 +74,44 @@ where
     F: FnOnce() -> R + 'static,
     R: 'static,
 {
-    let mut rt = runtime();
-    rt.spawn(future)
+    Builder::new().spawn(future)
+}
+
+/// Task factory, which can be used in order to configure the properties of a new task.
+#[derive(Debug, Default)]
+pub struct Builder {
+    name: Option<String>,
+    inherit_locals: bool,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self { name: None, inherit_locals: false }
+    }
+
+    /// Name the task. The name shows up in diagnostics, e.g. stack overflow reports.
+    pub fn name(mut self, name: String) -> Self {
+        self.name = Some(name);
+        self
+    }
+
+    /// Start the task out with the current values of the spawning task's `task_local!`s.
+    pub fn inherit_locals(mut self) -> Self {
+        self.inherit_locals = true;
+        self
+    }
+
+    pub fn spawn<F, R>(self, future: F) -> JoinHandle<R>
+    where
+        F: FnOnce() -> R + 'static,
+        R: 'static,
+    {
+        let locals = self.inherit_locals.then(local::inherited);
+        let mut rt = runtime();
+        let handle = rt.spawn(self.name, future);
+        if let Some(locals) = locals {
+            rt.tasks.get_mut(&handle.id).unwrap().locals = locals;
+        }
+        handle
+    }
 }
\ No newline at end of file
@@ -0,0 +1,90 @@
+//! Critical sections which must never suspend.
+//! Sections are only tracked with `debug_assertions`, in release builds the guard does nothing.
+//! Simulation mode keeps its injected yields out of sections in debug builds only.
+
+use std::marker::PhantomData;
+#[cfg(debug_assertions)]
+use std::{cell::Cell, panic::Location};
+
+#[cfg(debug_assertions)]
+use crate::runtime::runtime;
+
+#[cfg(debug_assertions)]
+thread_local! {
+    /// Nesting depth of `no_yield` sections and where the outermost one was entered.
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+    static OUTERMOST: Cell<Option<(usize, &'static Location<'static>)>> = const { Cell::new(None) };
+}
+
+/// Guard returned by `no_yield`. The current task must not suspend while it is alive.
+pub struct NoYieldGuard {
+    // Tied to the task which created it.
+    _marker: PhantomData<*const ()>,
+}
+
+/// Enter a section in which the current task must not suspend, e.g. while holding
+/// a `std::sync::MutexGuard` or while an invariant is temporarily broken.
+/// Yielding, blocking on any fib primitive or waiting for another task
+/// inside the section panics, naming both the section and the yield site.
+/// Sections may be nested.
+#[track_caller]
+pub fn no_yield() -> NoYieldGuard {
+    #[cfg(debug_assertions)]
+    if DEPTH.replace(DEPTH.get() + 1) == 0 {
+        OUTERMOST.set(Some((runtime().cur_task(), Location::caller())));
+    }
+    NoYieldGuard { _marker: PhantomData }
+}
+
+/// Whether the given task is inside a `no_yield` section.
+#[cfg(debug_assertions)]
+pub(crate) fn is_active(task: usize) -> bool {
+    OUTERMOST.get().is_some_and(|(owner, _)| owner == task)
+}
+
+#[cfg(not(debug_assertions))]
+pub(crate) fn is_active(_task: usize) -> bool {
+    false
+}
+
+/// Called right before the current task suspends, the caller location being the yield site.
+#[track_caller]
+pub(crate) fn check_suspend() {
+    #[cfg(debug_assertions)]
+    if let Some((task, entered_at)) = OUTERMOST.get()
+        && task == runtime().cur_task()
+    {
+        panic!(
+            "task suspended at {} inside a no_yield section entered at {}",
+            Location::caller(),
+            entered_at,
+        );
+    }
+}
+
+#[cfg(debug_assertions)]
+impl Drop for NoYieldGuard {
+    fn drop(&mut self) {
+        if DEPTH.replace(DEPTH.get() - 1) == 1 {
+            OUTERMOST.set(None);
+        }
+    }
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use crate::{runtime::runtime, task};
+
+    #[test]
+    #[should_panic(expected = "inside a no_yield section entered at")]
+    fn test_yield_inside_section() {
+        runtime().block_on(|| {
+            let _outer = task::no_yield();
+            {
+                let _inner = task::no_yield();
+            }
+            // Still inside the outer section.
+            task::yield_now();
+        });
+    }
+}
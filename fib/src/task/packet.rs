@@ -1,11 +1,15 @@
 //! Packet is for transferring data between tasks and the scheduler.
 
+use std::any::Any;
+
 use crate::task::BlockCause;
 
 pub(crate) enum Packet<R: 'static> {
     Yield,
     BlockOn(BlockCause),
     Result(Box<R>),
+    Cancelled,
+    Panic(Box<dyn Any + Send>),
 }
 
 impl<R: 'static> Packet<R> {
@@ -21,6 +25,14 @@ impl<R: 'static> Packet<R> {
         Box::new(Packet::BlockOn(cause))
     }
 
+    pub fn cancelled() -> Box::<Self> {
+        Box::new(Packet::Cancelled)
+    }
+
+    pub fn panic(payload: Box<dyn Any + Send>) -> Box::<Self> {
+        Box::new(Packet::Panic(payload))
+    }
+
     pub unsafe fn raw_ptr(self: Box::<Self>) -> usize {
         unsafe {
             Box::into_raw(self) as usize
@@ -0,0 +1,124 @@
+//! Tasks which may borrow from the spawning task, as `std::thread::scope` does for threads.
+
+use std::{cell::{Cell, OnceCell, RefCell}, marker::PhantomData, mem, rc::Rc};
+
+use crate::{runtime::runtime, task::{local, wait, JoinHandle, TaskId, TaskState}};
+
+/// Tasks spawned through a scope may borrow anything which outlives the scope.
+/// Every such task has finished by the time `scope` returns.
+/// They inherit the `task_local!` values of the spawning task.
+pub struct Scope<'scope, 'env: 'scope> {
+    tasks: RefCell<Vec<usize>>,
+    _scope: PhantomData<&'scope mut &'scope ()>,
+    _env: PhantomData<&'env mut &'env ()>,
+}
+
+pub struct ScopedJoinHandle<'scope, R> {
+    id: usize,
+    state: Rc<Cell<TaskState>>,
+    result: Rc<OnceCell<R>>,
+    _scope: PhantomData<&'scope ()>,
+}
+
+/// Run `f` with a scope for spawning borrowing tasks, then wait for all of them.
+/// If the current task unwinds out of `f`, e.g. because it has been cancelled,
+/// the tasks of the scope are cancelled and waited for before the unwinding goes on.
+#[track_caller]
+pub fn scope<'env, F, T>(f: F) -> T
+where
+    F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+{
+    let scope = Scope {
+        tasks: RefCell::new(vec![]),
+        _scope: PhantomData,
+        _env: PhantomData,
+    };
+    let guard = ScopeGuard(&scope);
+    let result = f(&scope);
+    mem::forget(guard);
+    scope.wait_all();
+    result
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    pub fn spawn<F, R>(&'scope self, f: F) -> ScopedJoinHandle<'scope, R>
+    where
+        F: FnOnce() -> R + 'scope,
+        R: 'scope,
+    {
+        let result = Rc::new(OnceCell::new());
+        let slot = result.clone();
+        let closure: Box<dyn FnOnce() + 'scope> = Box::new(move || {
+            let _ = slot.set(f());
+        });
+        // SAFETY: The scope waits for the task before anything it borrows goes away.
+        let closure: Box<dyn FnOnce() + 'static> = unsafe { mem::transmute(closure) };
+        let locals = local::inherited();
+        let rt = runtime();
+        let JoinHandle { id, state, .. } = rt.spawn(None, closure);
+        rt.tasks.get_mut(&id).unwrap().locals = locals;
+        self.tasks.borrow_mut().push(id);
+        ScopedJoinHandle { id, state, result, _scope: PhantomData }
+    }
+
+    /// Cancel every task of the scope which is still alive, except the current one.
+    pub fn cancel_all(&self) {
+        let rt = runtime();
+        for &id in self.tasks.borrow().iter() {
+            if id != rt.cur_task() && rt.cxs.contains_key(&id) {
+                rt.cancel(id);
+            }
+        }
+    }
+
+    #[track_caller]
+    fn wait_all(&self) {
+        let tasks = self.tasks.borrow().clone();
+        for id in tasks {
+            wait(TaskId(id));
+        }
+    }
+}
+
+/// Tears the scope down if the spawning task unwinds.
+struct ScopeGuard<'a, 'scope, 'env>(&'a Scope<'scope, 'env>);
+
+impl Drop for ScopeGuard<'_, '_, '_> {
+    fn drop(&mut self) {
+        self.0.cancel_all();
+        self.0.wait_all();
+    }
+}
+
+impl<R> ScopedJoinHandle<'_, R> {
+    pub fn id(&self) -> TaskId {
+        TaskId(self.id)
+    }
+
+    /// See `JoinHandle::is_finished`.
+    pub fn is_finished(&self) -> bool {
+        self.state.get() == TaskState::Finished
+    }
+
+    /// See `JoinHandle::cancel`.
+    pub fn cancel(&self) {
+        let rt = runtime();
+        if rt.cxs.contains_key(&self.id) {
+            rt.cancel(self.id);
+        }
+    }
+
+    /// Wait for the task to finish and take its result.
+    /// Panics if the task was cancelled.
+    #[track_caller]
+    pub fn join(self) -> R {
+        self.join_opt().expect("Task was cancelled")
+    }
+
+    /// Like `join`, but returns `None` if the task was cancelled.
+    #[track_caller]
+    pub(crate) fn join_opt(self) -> Option<R> {
+        wait(TaskId(self.id));
+        Rc::into_inner(self.result).and_then(OnceCell::into_inner)
+    }
+}
@@ -0,0 +1,290 @@
+//! Fiber stack bookkeeping.
+//! Every fiber stack is followed by a guard page. We remember where those guard pages are,
+//! so that a SIGSEGV caused by a fiber overrunning its stack can be reported properly
+//! instead of killing the process with a bare segmentation fault.
+
+use std::{cell::{Cell, RefCell}, fmt::{self, Write}, ops::Range, ptr, sync::{Once, OnceLock}};
+
+use context::stack::ProtectedFixedSizeStack;
+
+/// Size of the alternate signal stack. The handler formats a short message and walks the stack,
+/// but `SIGSTKSZ` is tight on some platforms.
+const ALT_STACK_SIZE: usize = 0x1000 * 16; // 64KB
+/// Capacity of the overflow message, longer task names are cut off.
+const REPORT_SIZE: usize = 256;
+/// Frames printed along with the overflow message, an overflow is mostly deep recursion.
+const BACKTRACE_DEPTH: usize = 64;
+
+// Not bound by the `libc` crate yet.
+#[cfg(target_env = "gnu")]
+unsafe extern "C" {
+    fn backtrace_symbols_fd(buffer: *const *mut libc::c_void, size: libc::c_int, fd: libc::c_int);
+}
+
+struct Guard {
+    id: usize,
+    name: Option<String>,
+    pages: Range<usize>,
+    stack_size: usize,
+}
+
+thread_local! {
+    static GUARDS: RefCell<Vec<Guard>> = const { RefCell::new(Vec::new()) };
+    static ALT_STACK: Cell<bool> = const { Cell::new(false) };
+}
+
+static HANDLER: Once = Once::new();
+static PREV_SIGSEGV: OnceLock<libc::sigaction> = OnceLock::new();
+static PREV_SIGBUS: OnceLock<libc::sigaction> = OnceLock::new();
+
+/// Remember the guard page of a freshly allocated fiber stack.
+pub(crate) fn register(id: usize, name: Option<&str>, stack: &ProtectedFixedSizeStack) {
+    let page_size = page_size();
+    let bottom = stack.bottom() as usize;
+    let guard = Guard {
+        id,
+        name: name.map(str::to_owned),
+        pages: bottom - page_size..bottom,
+        stack_size: stack.len(),
+    };
+    GUARDS.with(|guards| guards.borrow_mut().push(guard));
+}
+
+pub(crate) fn unregister(id: usize) {
+    // The thread local may already be gone if the runtime is torn down at thread exit.
+    let _ = GUARDS.try_with(|guards| guards.borrow_mut().retain(|guard| guard.id != id));
+}
+
+/// Install the overflow handler (once per process) and
+/// an alternate signal stack for the calling thread, see `install_alt_stack`.
+pub(crate) fn install_overflow_handler() {
+    HANDLER.call_once(|| unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle_fault as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        let mut prev: libc::sigaction = std::mem::zeroed();
+        libc::sigaction(libc::SIGSEGV, &action, &mut prev);
+        let _ = PREV_SIGSEGV.set(prev);
+        libc::sigaction(libc::SIGBUS, &action, &mut prev);
+        let _ = PREV_SIGBUS.set(prev);
+
+        // The first call loads the unwinder, which allocates, so get it over with here.
+        #[cfg(target_env = "gnu")]
+        {
+            let mut frames = [ptr::null_mut(); 1];
+            libc::backtrace(frames.as_mut_ptr(), 1);
+        }
+    });
+    install_alt_stack();
+}
+
+/// Install an alternate signal stack for the calling thread (once per thread).
+/// Every handler which may interrupt a fiber runs on it (`SA_ONSTACK`), as what is left
+/// of a fiber stack may not fit the handler, or the handler is there because none is left.
+pub(crate) fn install_alt_stack() {
+    if ALT_STACK.with(|installed| installed.replace(true)) {
+        return;
+    }
+    unsafe {
+        let stack = libc::mmap(
+            ptr::null_mut(),
+            ALT_STACK_SIZE,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        assert!(stack != libc::MAP_FAILED, "Failed to allocate alternate signal stack");
+        // Leaked on purpose: the alternate stack has to outlive every fiber of this thread.
+        let alt_stack = libc::stack_t {
+            ss_sp: stack,
+            ss_flags: 0,
+            ss_size: ALT_STACK_SIZE,
+        };
+        libc::sigaltstack(&alt_stack, ptr::null_mut());
+    }
+}
+
+/// A message formatted without allocating, as the fault may have hit inside `malloc`.
+struct Report {
+    buf: [u8; REPORT_SIZE],
+    len: usize,
+}
+
+impl Write for Report {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(REPORT_SIZE - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// Only async-signal-safe calls in here: no allocation, no locks (`eprintln!` locks stderr).
+/// The backtrace is written by glibc's `backtrace_symbols_fd`, which does not allocate either,
+/// as raw addresses with the dynamic symbols, if any. `addr2line` resolves the rest.
+extern "C" fn handle_fault(signum: libc::c_int, info: *mut libc::siginfo_t, _ucontext: *mut libc::c_void) {
+    let addr = unsafe { (*info).si_addr() } as usize;
+    let mut report = Report { buf: [0; REPORT_SIZE], len: 0 };
+    let found = GUARDS.try_with(|guards| {
+        let Ok(guards) = guards.try_borrow() else { return false };
+        let Some(guard) = guards.iter().find(|guard| guard.pages.contains(&addr)) else { return false };
+        let _ = writeln!(
+            report,
+            "fiber {}/{} overflowed its {} byte stack",
+            guard.id,
+            guard.name.as_deref().unwrap_or("<unnamed>"),
+            guard.stack_size,
+        );
+        true
+    }).unwrap_or(false);
+
+    if found {
+        unsafe {
+            libc::write(libc::STDERR_FILENO, report.buf.as_ptr().cast(), report.len);
+            #[cfg(target_env = "gnu")]
+            {
+                let mut frames = [ptr::null_mut(); BACKTRACE_DEPTH];
+                let depth = libc::backtrace(frames.as_mut_ptr(), BACKTRACE_DEPTH as libc::c_int);
+                backtrace_symbols_fd(frames.as_ptr(), depth, libc::STDERR_FILENO);
+            }
+            libc::abort();
+        }
+    }
+    unsafe {
+        // Not a fiber guard page. Restore whatever was installed before us and return,
+        // so the faulting instruction runs again and hits the previous handler.
+        let prev = if signum == libc::SIGBUS { &PREV_SIGBUS } else { &PREV_SIGSEGV };
+        match prev.get() {
+            Some(prev) => { libc::sigaction(signum, prev, ptr::null_mut()); },
+            None => { libc::signal(signum, libc::SIG_DFL); },
+        }
+    }
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Byte pattern painted over fresh stacks when stack usage tracking is enabled.
+const CANARY: u8 = 0xFD;
+/// Granularity of the per-name usage histogram.
+const BUCKET_SIZE: usize = 256;
+
+/// High-water mark of a painted fiber stack, shared between the task and its `JoinHandle`.
+pub(crate) struct StackUsage {
+    bottom: usize,
+    len: usize,
+    /// Set once the stack has been freed, after which only the last measurement is available.
+    freed: Cell<bool>,
+    high_water: Cell<usize>,
+}
+
+impl StackUsage {
+    /// Fill the whole stack with the canary pattern.
+    /// NOTICE This touches, and thus commits, every page of the stack.
+    pub(crate) fn paint(stack: &ProtectedFixedSizeStack) -> Self {
+        let usage = Self {
+            bottom: stack.bottom() as usize,
+            len: stack.len(),
+            freed: Cell::new(false),
+            high_water: Cell::new(0),
+        };
+        unsafe { ptr::write_bytes(usage.bottom as *mut u8, CANARY, usage.len) };
+        usage
+    }
+
+    /// Deepest offset (in bytes, from the top of the stack) touched so far.
+    pub(crate) fn measure(&self) -> usize {
+        if !self.freed.get() {
+            let stack = unsafe { std::slice::from_raw_parts(self.bottom as *const u8, self.len) };
+            let untouched = stack.iter().take_while(|&&byte| byte == CANARY).count();
+            let used = self.len - untouched;
+            self.high_water.set(self.high_water.get().max(used));
+        }
+        self.high_water.get()
+    }
+
+    /// Take the final measurement before the stack is freed.
+    /// Must be called by whoever frees the stack, later calls return that measurement.
+    pub(crate) fn finish(&self) -> usize {
+        let used = self.measure();
+        self.freed.set(true);
+        used
+    }
+}
+
+/// Stack usage of finished tasks, grouped by task name.
+#[derive(Debug, Default)]
+pub struct StackReport {
+    entries: Vec<(String, StackHistogram)>,
+}
+
+#[derive(Debug, Default)]
+struct StackHistogram {
+    buckets: Vec<u64>,
+    samples: u64,
+    max: usize,
+}
+
+/// Summary of the stack usage of all finished tasks sharing a name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackStats {
+    pub name: String,
+    pub samples: u64,
+    /// Deepest usage observed, in bytes.
+    pub max: usize,
+    /// 99th percentile usage, rounded up to 256 bytes.
+    pub p99: usize,
+}
+
+impl StackReport {
+    pub(crate) fn record(&mut self, name: Option<&str>, used: usize) {
+        let name = name.unwrap_or("<unnamed>");
+        let histogram = match self.entries.iter().position(|(n, _)| n == name) {
+            Some(i) => &mut self.entries[i].1,
+            None => {
+                self.entries.push((name.to_owned(), StackHistogram::default()));
+                &mut self.entries.last_mut().unwrap().1
+            },
+        };
+        let bucket = used / BUCKET_SIZE;
+        if histogram.buckets.len() <= bucket {
+            histogram.buckets.resize(bucket + 1, 0);
+        }
+        histogram.buckets[bucket] += 1;
+        histogram.samples += 1;
+        histogram.max = histogram.max.max(used);
+    }
+
+    pub fn stats(&self) -> Vec<StackStats> {
+        self.entries.iter().map(|(name, histogram)| {
+            let threshold = histogram.samples - histogram.samples / 100;
+            let mut seen = 0;
+            let p99_bucket = histogram.buckets.iter()
+                .position(|&count| {
+                    seen += count;
+                    seen >= threshold
+                })
+                .unwrap_or(0);
+            StackStats {
+                name: name.clone(),
+                samples: histogram.samples,
+                max: histogram.max,
+                p99: ((p99_bucket + 1) * BUCKET_SIZE).min(histogram.max.next_multiple_of(BUCKET_SIZE)),
+            }
+        }).collect()
+    }
+}
+
+impl std::fmt::Display for StackReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:<24} {:>8} {:>8} {:>8}", "task", "samples", "max", "p99")?;
+        for stats in self.stats() {
+            writeln!(f, "{:<24} {:>8} {:>8} {:>8}", stats.name, stats.samples, stats.max, stats.p99)?;
+        }
+        Ok(())
+    }
+}
@@ -1,37 +1,61 @@
 //! Task management module
 //! Task is our representation of a fiber.
 
-use std::{cell::OnceCell, rc::Rc};
+use std::{backtrace::Backtrace, cell::{Cell, OnceCell}, panic::{self, Location}, rc::Rc, time::{Duration, Instant}};
 
 use context::{stack::ProtectedFixedSizeStack, Transfer};
 
 use crate::runtime::runtime;
+use crate::task::local::TaskLocals;
 use crate::task::packet::Packet;
-use crate::task::wait;
-use crate::{runtime::task_entry, task::BlockCause};
-use crate::config::*;
+use crate::task::stack::{self, StackUsage};
+use crate::task::{wait, TaskId};
+use crate::{runtime::{metrics, task_entry, trace::{self, TaskSpan}}, task::BlockCause};
 
+/// What a task is doing, see `TaskRef::state`.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub(crate) enum TaskState {
+pub enum TaskState {
     Ready,
     Running,
-    BlockOn(BlockCause),
+    Blocked(BlockCause),
     Finished,
 }
 
+/// Runtime-side bookkeeping of a live task.
+pub(crate) struct TaskInfo {
+    pub(crate) name: Option<String>,
+    pub(crate) locals: TaskLocals,
+    pub(crate) spawned_at: Instant,
+    /// Where the task last suspended, and the backtrace if the runtime captures them.
+    pub(crate) suspended_at: Option<&'static Location<'static>>,
+    pub(crate) backtrace: Option<Backtrace>,
+    pub(crate) polls: u64,
+    pub(crate) busy: Duration,
+    /// Shared with the task itself and its handles, which outlive it.
+    pub(crate) cpu_time: Rc<Cell<Duration>>,
+    /// Shared with the task itself and the `TaskRef`s of it.
+    pub(crate) state: Rc<Cell<TaskState>>,
+    pub(crate) span: TaskSpan,
+}
+
 pub(crate) struct Task<R: 'static> {
     pub(crate) id: usize,
     pub(crate) stack: ProtectedFixedSizeStack,
-    pub(crate) state: TaskState,
+    pub(crate) stack_usage: Option<Rc<StackUsage>>,
+    pub(crate) state: Rc<Cell<TaskState>>,
     pub(crate) result: Rc<OnceCell<R>>,
+    pub(crate) cpu_time: Rc<Cell<Duration>>,
 }
 
 impl<R: 'static> Task<R> {
-    pub(crate) fn new<F>(id: usize, future: F) -> (Self, context::Context)
+    pub(crate) fn new<F>(id: usize, name: Option<&str>, stack_size: usize, paint: bool, future: F) -> (Self, context::Context)
     where
         F: FnOnce() -> R + 'static,
     {
-        let stack = ProtectedFixedSizeStack::new(STACK_SIZE).unwrap();
+        let stack = ProtectedFixedSizeStack::new(stack_size).unwrap();
+        stack::register(id, name, &stack);
+        // Paint before the initial frame is pushed onto the stack.
+        let stack_usage = paint.then(|| Rc::new(StackUsage::paint(&stack)));
         let cx = unsafe { context::Context::new(&stack, task_entry::<R>) };
         
         // Set up initial state.
@@ -44,12 +68,53 @@ impl<R: 'static> Task<R> {
         (Self {
             id,
             stack,
-            state: TaskState::Ready,
+            stack_usage,
+            state: Rc::new(Cell::new(TaskState::Ready)),
             result: Rc::new(OnceCell::new()),
+            cpu_time: Rc::new(Cell::new(Duration::ZERO)),
         }, to_task.context)
     }
 }
 
+impl<R: 'static> Task<R> {
+    fn finish(&mut self) {
+        let rt = runtime();
+        trace::finished(self.id);
+        // rt.cxs.remove(&self.id);
+        rt.get_cur_cx().unwrap();
+        rt.wake_joiners(self.id);
+        rt.metrics.completed += 1;
+        #[cfg(feature = "watchdog")]
+        if let Some(watchdog) = &rt.watchdog {
+            watchdog.finished(self.id);
+        }
+        let info = rt.tasks.remove(&self.id).unwrap();
+        rt.record_cpu_time(info.name.clone(), self.cpu_time.get());
+        if let Some(usage) = &self.stack_usage {
+            rt.stack_report.record(info.name.as_deref(), usage.finish());
+        }
+        self.state.set(TaskState::Finished);
+    }
+}
+
+impl<R: 'static> Drop for Task<R> {
+    fn drop(&mut self) {
+        // A task torn down without finishing still has its stack mapped until the fields drop.
+        if let Some(usage) = &self.stack_usage {
+            usage.finish();
+        }
+        // It is only torn down by `Runtime::shutdown`, which still knows its name.
+        if self.state.get() != TaskState::Finished {
+            let rt = runtime();
+            if let Some(info) = rt.tasks.remove(&self.id) {
+                rt.record_cpu_time(info.name, self.cpu_time.get());
+            }
+        }
+        self.state.set(TaskState::Finished);
+        stack::unregister(self.id);
+    }
+}
+
 pub(crate) trait AnyTask {
     fn id(&self) -> usize;
     fn resume(&mut self);
@@ -66,12 +131,16 @@ impl<R: 'static> AnyTask for Task<R> {
         let rt = runtime();
         // Weird, tighly coupled, ugly. But for simplicity we keep it like this.
         if let Some(cx) = rt.get_cur_cx() {
-            match self.state {
+            match self.state.get() {
                 TaskState::Ready => {
-                    self.state = TaskState::Running;
+                    self.state.set(TaskState::Running);
                     
                     let mut to_task = Transfer::new(cx, 0);
+                    let started = rt.track_cpu_time.then(metrics::thread_cpu_time);
                     let mut from_task = unsafe { to_task.context.resume(0) };
+                    if let Some(started) = started {
+                        self.cpu_time.set(self.cpu_time.get() + metrics::thread_cpu_time().saturating_sub(started));
+                    }
                     rt.set_cur_cx(from_task.context);
                     
                     let packet = unsafe {
@@ -81,22 +150,22 @@ impl<R: 'static> AnyTask for Task<R> {
                     match packet {
                         Packet::Result(result) => {
                             assert!(self.result.set(*result).is_ok());
-                            // rt.cxs.remove(&self.id);
-                            rt.get_cur_cx().unwrap();
-                            self.state = TaskState::Finished;
+                            self.finish();
+                        },
+                        Packet::Cancelled => self.finish(),
+                        Packet::Panic(payload) => {
+                            self.finish();
+                            panic::resume_unwind(payload);
                         },
                         Packet::Yield =>  {
-                            self.state = TaskState::Ready;
+                            self.state.set(TaskState::Ready);
                         },
                         Packet::BlockOn(cause) => {
-                            self.state = TaskState::BlockOn(cause);
+                            self.state.set(TaskState::Blocked(cause));
                         }
                     }
                 },
-                TaskState::BlockOn(ref cause) => match cause {
-                    _ => unreachable!(),
-                },
-                TaskState::Finished|TaskState::Running => unreachable!(),
+                TaskState::Blocked(_)|TaskState::Finished|TaskState::Running => unreachable!(),
             }
         } else {
             panic!("No context for task {}", self.id);
@@ -104,29 +173,63 @@ impl<R: 'static> AnyTask for Task<R> {
     }
 
     fn state(&self) -> TaskState {
-        self.state
+        self.state.get()
     }
 
     fn trans_state(&mut self, new_state: TaskState) {
-        self.state = new_state;
+        self.state.set(new_state);
     }
 }
 
 pub struct JoinHandle<R: 'static> {
     pub(crate) id: usize,
+    pub(crate) state: Rc<Cell<TaskState>>,
     pub(crate) result: Rc<OnceCell<R>>,
+    pub(crate) stack_usage: Option<Rc<StackUsage>>,
+    pub(crate) cpu_time: Rc<Cell<Duration>>,
 }
 
 impl<R: 'static> JoinHandle<R> {
+    pub fn id(&self) -> TaskId {
+        TaskId(self.id)
+    }
+
+    /// Whether the task has finished, by returning, panicking or being cancelled.
     pub fn is_finished(&self) -> bool {
-        self.result.get().is_some()
+        self.state.get() == TaskState::Finished
+    }
+
+    /// Deepest stack usage of the task in bytes, measured now if the task is still alive.
+    /// Returns `None` unless stack usage tracking was enabled when the task was spawned,
+    /// see `Runtime::track_stack_usage`.
+    pub fn stack_usage(&self) -> Option<usize> {
+        self.stack_usage.as_ref().map(|usage| usage.measure())
     }
 
+    /// Thread CPU time the task has spent running, up to its last suspension.
+    /// Zero unless the runtime tracks CPU time, see `Runtime::track_cpu_time`.
+    pub fn cpu_time(&self) -> Duration {
+        self.cpu_time.get()
+    }
+
+    /// Make the task unwind from the point where it is suspended the next time it is scheduled,
+    /// so that its destructors run. A task which has not started yet never starts.
+    /// Does nothing if the task has already finished.
+    pub fn cancel(&self) {
+        let rt = runtime();
+        if rt.cxs.contains_key(&self.id) {
+            rt.cancel(self.id);
+        }
+    }
+
+    /// Wait for the task to finish and take its result.
+    /// Panics if the task was cancelled, e.g. by `Runtime::shutdown` or `cancel`.
+    #[track_caller]
     pub fn join(self) -> R {
-        wait(self.id);
+        wait(TaskId(self.id));
         Rc::into_inner(self.result)
             .unwrap()
             .into_inner()
-            .unwrap()
+            .expect("Task was cancelled")
     }
 }
@@ -0,0 +1,297 @@
+//! Timers, driven by a clock which can be paused for tests.
+//! While paused, time only moves on through `advance`, or, with auto-advance,
+//! by jumping straight to the next timer deadline once every fiber is blocked.
+//! Tests involving long timeouts thus finish instantly and deterministically.
+
+use std::{collections::BTreeMap, time::{Duration, Instant}};
+
+use crate::{runtime::runtime, select::Selectable, task::BlockCause};
+
+/// Set in the key a sleeping task blocks on, which tells timers apart
+/// from primitive addresses and task ids, e.g. for the model checker.
+pub(crate) const TIMER_KEY: usize = 1 << (usize::BITS - 1);
+
+pub(crate) struct Timers {
+    /// Virtual time at `base_real`.
+    base: Instant,
+    base_real: Instant,
+    paused: bool,
+    auto_advance: bool,
+    /// Sleeping tasks, by deadline. The sequence number keeps equal deadlines in order.
+    entries: BTreeMap<(Instant, u64), usize>,
+    next_seq: u64,
+}
+
+impl Timers {
+    pub(crate) fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            base: now,
+            base_real: now,
+            paused: false,
+            auto_advance: true,
+            entries: BTreeMap::new(),
+            next_seq: 0,
+        }
+    }
+
+    pub(crate) fn now(&self) -> Instant {
+        if self.paused {
+            self.base
+        } else {
+            self.base + self.base_real.elapsed()
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.entries.keys().next().map(|&(deadline, _)| deadline)
+    }
+
+    pub(crate) fn pause(&mut self) {
+        if !self.paused {
+            self.base = self.now();
+            self.paused = true;
+        }
+    }
+
+    pub(crate) fn resume(&mut self) {
+        if self.paused {
+            self.base_real = Instant::now();
+            self.paused = false;
+        }
+    }
+
+    pub(crate) fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Whether the clock jumps to the next deadline once every task is blocked.
+    pub(crate) fn auto_advances(&self) -> bool {
+        self.paused && self.auto_advance
+    }
+
+    /// Wake up `task` at `deadline`, returning the key of the timer.
+    pub(crate) fn insert(&mut self, deadline: Instant, task: usize) -> (Instant, u64) {
+        let key = (deadline, self.next_seq);
+        self.next_seq += 1;
+        self.entries.insert(key, task);
+        key
+    }
+
+    pub(crate) fn remove(&mut self, key: (Instant, u64)) {
+        self.entries.remove(&key);
+    }
+
+    /// Remove the timers which are due, returning the tasks to wake up.
+    pub(crate) fn take_due(&mut self) -> Vec<usize> {
+        let now = self.now();
+        let mut due = vec![];
+        while let Some(entry) = self.entries.first_entry()
+            && entry.key().0 <= now
+        {
+            due.push(entry.remove());
+        }
+        due
+    }
+
+    /// Move paused time forward.
+    pub(crate) fn advance_to(&mut self, instant: Instant) {
+        assert!(self.paused, "time is not paused");
+        self.base = self.base.max(instant);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// The current time, as seen by fibers.
+pub fn now() -> Instant {
+    runtime().timers.now()
+}
+
+/// Freeze time. It only moves on through `advance` or auto-advance from now on.
+pub fn pause() {
+    runtime().timers.pause();
+}
+
+/// Let time flow again, from where it was paused.
+pub fn resume() {
+    runtime().timers.resume();
+}
+
+pub fn is_paused() -> bool {
+    runtime().timers.is_paused()
+}
+
+/// Whether paused time jumps straight to the next timer deadline once every fiber is blocked.
+/// On by default. Turn it off in order to step time only through `advance`.
+pub fn set_auto_advance(enabled: bool) {
+    runtime().timers.auto_advance = enabled;
+}
+
+/// Move paused time forward by `duration`, waking up the fibers whose timers are due.
+/// They run once the calling fiber yields. Panics if time is not paused.
+pub fn advance(duration: Duration) {
+    let rt = runtime();
+    let to = rt.timers.now() + duration;
+    rt.timers.advance_to(to);
+    rt.fire_timers();
+}
+
+/// Suspend the current fiber for at least `duration`.
+#[track_caller]
+pub fn sleep(duration: Duration) {
+    sleep_until(now() + duration);
+}
+
+/// Suspend the current fiber until `deadline`.
+#[track_caller]
+pub fn sleep_until(deadline: Instant) {
+    let rt = runtime();
+    while rt.timers.now() < deadline {
+        let key = rt.timers.insert(deadline, rt.cur_task());
+        let _entry = TimerEntry { key };
+        rt.block(BlockCause::Timer, TIMER_KEY | key.1 as usize);
+    }
+}
+
+/// Removes the timer of a fiber which is torn down while sleeping.
+struct TimerEntry {
+    key: (Instant, u64),
+}
+
+impl Drop for TimerEntry {
+    fn drop(&mut self) {
+        runtime().timers.remove(self.key);
+    }
+}
+
+/// A deadline, mostly useful as a timeout branch of `select!`.
+/// Pass `&mut sleep` to keep the same deadline across several selects.
+#[derive(Debug)]
+pub struct Sleep {
+    deadline: Instant,
+    key: Option<(Instant, u64)>,
+}
+
+impl Sleep {
+    pub fn new(duration: Duration) -> Self {
+        Self::until(now() + duration)
+    }
+
+    pub fn until(deadline: Instant) -> Self {
+        Self { deadline, key: None }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    pub fn is_elapsed(&self) -> bool {
+        now() >= self.deadline
+    }
+
+    /// Suspend the current fiber until the deadline.
+    #[track_caller]
+    pub fn wait(self) {
+        sleep_until(self.deadline);
+    }
+}
+
+impl Selectable for Sleep {
+    type Output = ();
+
+    fn try_select(&mut self) -> Option<()> {
+        self.is_elapsed().then_some(())
+    }
+
+    fn register(&mut self) {
+        let timers = &mut runtime().timers;
+        if let Some(key) = self.key.take() {
+            timers.remove(key);
+        }
+        self.key = Some(timers.insert(self.deadline, runtime().cur_task()));
+    }
+
+    fn deregister(&mut self) {
+        if let Some(key) = self.key.take() {
+            runtime().timers.remove(key);
+        }
+    }
+}
+
+impl Selectable for &mut Sleep {
+    type Output = ();
+
+    fn try_select(&mut self) -> Option<()> {
+        (**self).try_select()
+    }
+
+    fn register(&mut self) {
+        (**self).register();
+    }
+
+    fn deregister(&mut self) {
+        (**self).deregister();
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.deregister();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use crate::task;
+
+    use super::*;
+
+    #[fib::test(paused_time)]
+    fn test_retry_backoff() {
+        let real = Instant::now();
+        let start = now();
+        let attempts = Rc::new(Cell::new(0));
+        let handle = task::spawn({
+            let attempts = attempts.clone();
+            move || {
+                while attempts.get() < 3 {
+                    attempts.set(attempts.get() + 1);
+                    sleep(Duration::from_secs(30));
+                }
+            }
+        });
+        handle.join();
+        assert_eq!(attempts.get(), 3);
+        assert_eq!(now() - start, Duration::from_secs(90));
+        assert!(real.elapsed() < Duration::from_secs(1));
+    }
+
+    #[fib::test(paused_time)]
+    fn test_advance() {
+        set_auto_advance(false);
+        let woken = Rc::new(Cell::new(false));
+        task::spawn({
+            let woken = woken.clone();
+            move || {
+                sleep(Duration::from_secs(10));
+                woken.set(true);
+            }
+        });
+        task::yield_now();
+        advance(Duration::from_secs(5));
+        task::yield_now();
+        assert!(!woken.get());
+        advance(Duration::from_secs(5));
+        task::yield_now();
+        assert!(woken.get());
+    }
+}
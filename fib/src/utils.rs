@@ -1,6 +1,8 @@
 //! Lib-private utilities
 
-use std::{cell::UnsafeCell, fmt::Debug};
+use std::{cell::UnsafeCell, fmt::{self, Debug, Display}};
+#[cfg(any(feature = "watchdog", feature = "profile"))]
+use std::{collections::HashMap, ffi::c_void};
 
 /// A single-thread cell. \
 /// SAFETY \
@@ -30,7 +32,54 @@ impl<R> STCell<R> {
         unsafe { (*self.inner.get()).as_ref().unwrap() }
     }
 
+    #[allow(clippy::mut_from_ref)]
     pub(crate) fn get_mut(&self) -> &mut R {
         unsafe { (*self.inner.get()).as_mut().unwrap() }
     }
-}
\ No newline at end of file
+}
+
+/// `task <id>/<name>`, the label of a task in dumps, timelines and profiles.
+pub(crate) fn task_label(id: usize, name: Option<&str>) -> TaskLabel<'_> {
+    TaskLabel { id, name }
+}
+
+pub(crate) struct TaskLabel<'a> {
+    id: usize,
+    name: Option<&'a str>,
+}
+
+impl Display for TaskLabel<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "task {}/{}", self.id, self.name.unwrap_or("<unnamed>"))
+    }
+}
+
+/// Names of the frames of a stack walked from the signal handler `handler`, innermost first,
+/// without the frames of the handler itself. Resolved names are cached by address.
+#[cfg(any(feature = "watchdog", feature = "profile"))]
+pub(crate) fn symbolize(frames: &[usize], handler: &str, cache: &mut HashMap<usize, Vec<String>>) -> Vec<String> {
+    let mut names = vec![];
+    for (i, &ip) in frames.iter().enumerate() {
+        // Return addresses point past the call, look the call itself up.
+        let lookup = if i == 0 { ip } else { ip - 1 };
+        let symbols = cache.entry(lookup).or_insert_with(|| {
+            let mut symbols = vec![];
+            backtrace::resolve(lookup as *mut c_void, |symbol| {
+                symbols.push(match symbol.name() {
+                    Some(name) => format!("{:#}", name),
+                    None => format!("{:#x}", ip),
+                });
+            });
+            if symbols.is_empty() {
+                symbols.push(format!("{:#x}", ip));
+            }
+            symbols
+        });
+        names.extend(symbols.iter().cloned());
+    }
+    // The handler is followed by the signal trampoline, then the interrupted frame.
+    if let Some(i) = names.iter().position(|name| name.contains(handler)) {
+        names.drain(..(i + 2).min(names.len()));
+    }
+    names
+}